@@ -0,0 +1,264 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+use std::hash;
+use std::str::FromStr;
+use bech32::primitives::decode::{CheckedHrpstring, CheckedHrpstringError};
+use bech32::{Bech32m, Hrp};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use crate::{Hash, HASH_LEN, PUBKEY_LEN};
+
+/** Human-readable prefix of addresses on the main network, and of any address not yet placed on another. */
+pub const MAINNET_HRP:&str = "rg";
+/** Prefix for test networks, so their addresses can't be pasted into a mainnet wallet. */
+pub const TESTNET_HRP:&str = "trg";
+
+/** What an address's payload is. The first byte of the bech32m data says which. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AddressKind {
+    /** Payload is a 32 byte ed25519 public key. */
+    Key,
+    /** Payload is the hash of a `MultisigPolicy`. */
+    Multisig,
+    /** The `from` of every coinbase. No payload and no key, so nothing can be spent from it. */
+    Coinbase
+}
+
+impl AddressKind {
+    fn version(&self) -> u8 {
+        match self {
+            AddressKind::Key => 0,
+            AddressKind::Multisig => 1,
+            AddressKind::Coinbase => 0xff
+        }
+    }
+
+    fn from_version(version:u8) -> Result<Self, AddressError> {
+        match version {
+            0 => Ok(AddressKind::Key),
+            1 => Ok(AddressKind::Multisig),
+            0xff => Ok(AddressKind::Coinbase),
+            version => Err(AddressError::UnknownKind(version))
+        }
+    }
+
+    fn payload_len(&self) -> usize {
+        match self {
+            AddressKind::Key => PUBKEY_LEN,
+            AddressKind::Multisig => HASH_LEN,
+            AddressKind::Coinbase => 0
+        }
+    }
+}
+
+/** Why a string is not an address. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    Malformed(String),
+    BadChecksum,
+    WrongNetwork { expected:String, got:String },
+    UnknownKind(u8),
+    WrongLength { kind:AddressKind, len:usize }
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::Malformed(reason) => write!(f, "malformed address: {}", reason),
+            AddressError::BadChecksum => write!(f, "address checksum does not match, check it for typos"),
+            AddressError::WrongNetwork { expected, got } => write!(f, "address is for network {}, not {}", got, expected),
+            AddressError::UnknownKind(version) => write!(f, "unknown address kind {}", version),
+            AddressError::WrongLength { kind, len } => write!(f, "{:?} address with a {} byte payload", kind, len)
+        }
+    }
+}
+
+impl Error for AddressError {}
+
+/** Where gold can be sent. Written as bech32m: the network prefix and `1` (`rg1` on mainnet) followed
+    by the kind, the payload and a checksum that catches any typo of up to four characters.
+    The prefix only matters for writing the address down. Comparisons, the binary encoding and the
+    account tree ignore it, so `Client` and `Keystore` place addresses on their network as they go.
+ */
+#[derive(Clone)]
+pub struct Address {
+    kind:AddressKind,
+    payload:Vec<u8>,
+    /** Always a valid, lowercase prefix. */
+    hrp:Cow<'static, str>
+}
+
+impl PartialEq for Address {
+    fn eq(&self, other:&Self) -> bool {
+        self.kind == other.kind && self.payload == other.payload
+    }
+}
+
+impl Eq for Address {}
+
+impl PartialOrd for Address {
+    fn partial_cmp(&self, other:&Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Address {
+    fn cmp(&self, other:&Self) -> Ordering {
+        (self.kind, &self.payload).cmp(&(other.kind, &other.payload))
+    }
+}
+
+impl hash::Hash for Address {
+    fn hash<H:hash::Hasher>(&self, state:&mut H) {
+        self.kind.hash(state);
+        self.payload.hash(state);
+    }
+}
+
+fn parse_hrp(hrp:&str) -> Hrp {
+    Hrp::parse(hrp).unwrap_or_else(|err| panic!("invalid address prefix {}: {}", hrp, err))
+}
+
+impl Address {
+    fn new(kind:AddressKind, payload:Vec<u8>) -> Self {
+        Address { kind, payload, hrp: Cow::Borrowed(MAINNET_HRP) }
+    }
+
+    /** The address that belongs to an ed25519 public key. */
+    pub fn from_public_key(public_key:&[u8]) -> Self {
+        Address::new(AddressKind::Key, public_key.to_vec())
+    }
+
+    /** The address of the multisig policy hashing to `policy_hash`. See `MultisigPolicy::address`. */
+    pub fn from_multisig_hash(policy_hash:&Hash) -> Self {
+        Address::new(AddressKind::Multisig, policy_hash.to_vec())
+    }
+
+    pub fn coinbase() -> Self {
+        Address::new(AddressKind::Coinbase, vec![])
+    }
+
+    /** The same address written with the prefix `hrp`, which must be valid. */
+    pub fn on_network(mut self, hrp:&str) -> Self {
+        if hrp == MAINNET_HRP {
+            self.hrp = Cow::Borrowed(MAINNET_HRP);
+        }
+        else if hrp != self.hrp {
+            self.hrp = Cow::Owned(parse_hrp(hrp).to_lowercase());
+        }
+        self
+    }
+
+    /** The prefix this address is written with. */
+    pub fn network(&self) -> &str {
+        &self.hrp
+    }
+
+    pub fn kind(&self) -> AddressKind {
+        self.kind
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /** Whether gold can be sent here. Only coinbases pay to nothing at all. */
+    pub fn can_receive(&self) -> bool {
+        self.kind != AddressKind::Coinbase
+    }
+
+    /** The kind byte followed by the payload. What the binary encoding and the account tree use. */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + self.payload.len());
+        bytes.push(self.kind.version());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    /** Whether `hrp` can prefix an address. */
+    pub fn is_valid_hrp(hrp:&str) -> bool {
+        Hrp::parse(hrp).is_ok()
+    }
+
+    /** This address under the prefix `hrp`, which must be valid. See `Blockchain::format_address`. */
+    pub fn encode(&self, hrp:&str) -> String {
+        bech32::encode_lower::<Bech32m>(parse_hrp(hrp), &self.to_bytes()).unwrap()
+    }
+
+    /** Parses an address, which has to carry the prefix `hrp`. See `Blockchain::parse_address`. */
+    pub fn decode(s:&str, hrp:&str) -> Result<Self, AddressError> {
+        let address:Address = s.parse()?;
+        if address.network() != hrp.to_lowercase() {
+            return Err(AddressError::WrongNetwork { expected: hrp.to_string(), got: address.network().to_string() });
+        }
+        Ok(address)
+    }
+
+    /** Inverse of `to_bytes`. */
+    pub fn from_bytes(bytes:&[u8]) -> Result<Self, AddressError> {
+        let (version, payload) = bytes.split_first().ok_or_else(|| AddressError::Malformed("empty".to_string()))?;
+        let kind = AddressKind::from_version(*version)?;
+        if payload.len() != kind.payload_len() {
+            return Err(AddressError::WrongLength { kind, len: payload.len() });
+        }
+        Ok(Address::new(kind, payload.to_vec()))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        bech32::encode_lower_to_fmt::<Bech32m, _>(f, parse_hrp(&self.hrp), &self.to_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    /** Takes an address on any network, keeping its prefix. Use `Address::decode` to insist on one. */
+    fn from_str(s:&str) -> Result<Self, Self::Err> {
+        let checked = CheckedHrpstring::new::<Bech32m>(s).map_err(|err| match err {
+            CheckedHrpstringError::Checksum(_) => AddressError::BadChecksum,
+            err => AddressError::Malformed(format!("{:?}", err))
+        })?;
+        let address = Address::from_bytes(&checked.byte_iter().collect::<Vec<u8>>())?;
+        Ok(address.on_network(checked.hrp().as_str()))
+    }
+}
+
+impl Serialize for Address {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Address, AddressError, MAINNET_HRP, TESTNET_HRP};
+
+    #[test]
+    fn keeps_its_network_prefix() {
+        let address = Address::from_public_key(&[7; 32]).on_network(TESTNET_HRP);
+        let written = address.to_string();
+        assert!(written.starts_with("trg1"));
+        let parsed:Address = written.parse().unwrap();
+        assert_eq!(parsed.network(), TESTNET_HRP);
+        assert_eq!(serde_json::from_str::<Address>(&serde_json::to_string(&address).unwrap()).unwrap().to_string(), written);
+        //the prefix is only how it's written, it's still the same account
+        assert_eq!(parsed, Address::from_public_key(&[7; 32]));
+        assert_eq!(Address::decode(&written, MAINNET_HRP).err(), Some(AddressError::WrongNetwork { expected: MAINNET_HRP.to_string(), got: TESTNET_HRP.to_string() }));
+        assert!(Address::decode(&written.to_uppercase(), TESTNET_HRP).is_ok());
+    }
+}
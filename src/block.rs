@@ -1,121 +1,224 @@
 use std::collections::BTreeMap;
-use std::ptr::null;
 use super::*;
+use crate::merkle::{merkle_proof, merkle_root, MerkleProof};
+use crate::state::{AccountProof, AccountState};
+use crate::encoding::{DecodeError, Decoder, Encoder};
 use serde::*;
 use serde::Serializer;
-use serde_json::*;
-use crate::blockchain::{COINBASE_REWARD, POW_LEADING_ZEROS};
 
 
-#[derive(Serialize, Clone, Debug)]
-pub struct Block {
-    pub reward_addr:Address,
+/** Everything proof-of-work commits to. The body is bound through the two roots,
+    so hashing the header stays cheap no matter how many accounts or transactions there are.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BlockHeader {
     pub prev_block_hash:Hash,
+    pub transactions_root:Hash,
+    pub state_root:Hash,
+    pub reward_addr:Address,
+    pub coinbase_reward:u128,
+    pub coinbase_maturity:u32,
+    pub timestamp:u128,
     pub pow_target:Hash,
     pub proof:u128,
-    pub coinbase_reward:u16,
-    pub transactions:BTreeMap<Hash, Transaction>,
-    pub chain_length:u32,
-    pub timestamp:u128,
-    pub balances:BTreeMap<Address, u128>,
-    pub next_nonce:BTreeMap<Address, u128>
+    pub chain_length:u32
 }
-impl Default for Block {
-    fn default() -> Block {
-        Block {
-            reward_addr:"0".to_string(),
-            prev_block_hash:Hash(vec![0;32]),
-            pow_target:calc_pow_target(),
-            proof:0,
-            coinbase_reward:COINBASE_REWARD,
-            transactions:BTreeMap::new(),
-            chain_length:0,
-            timestamp:now(),
-            balances:BTreeMap::new(),
-            next_nonce:BTreeMap::new()
-        }
+
+impl BlockHeader {
+    fn encode(&self, encoder:&mut Encoder) {
+        encoder.hash(&self.prev_block_hash);
+        encoder.hash(&self.transactions_root);
+        encoder.hash(&self.state_root);
+        encoder.address(&self.reward_addr);
+        encoder.u128(self.coinbase_reward);
+        encoder.u32(self.coinbase_maturity);
+        encoder.u128(self.timestamp);
+        encoder.hash(&self.pow_target);
+        encoder.u128(self.proof);
+        encoder.u32(self.chain_length);
     }
-}
 
-fn calc_pow_target () -> Hash {
-    let mut pow_target:Hash = Hash(vec![0xff;32]);
-    for i in 0..POW_LEADING_ZEROS/2 {
-        pow_target[i] = 0x00;
+    fn decode(decoder:&mut Decoder) -> Result<Self, DecodeError> {
+        Ok(BlockHeader {
+            prev_block_hash: decoder.hash()?,
+            transactions_root: decoder.hash()?,
+            state_root: decoder.hash()?,
+            reward_addr: decoder.address()?,
+            coinbase_reward: decoder.u128()?,
+            coinbase_maturity: decoder.u32()?,
+            timestamp: decoder.u128()?,
+            pow_target: decoder.hash()?,
+            proof: decoder.u128()?,
+            chain_length: decoder.u32()?
+        })
+    }
+
+    /** Canonical encoding. It's what `id` hashes, so it's also the proof-of-work preimage. */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::versioned();
+        self.encode(&mut encoder);
+        encoder.finish()
+    }
+
+    pub fn from_bytes(bytes:&[u8]) -> Result<Self, DecodeError> {
+        let mut decoder = Decoder::new(bytes);
+        decoder.version()?;
+        let header = BlockHeader::decode(&mut decoder)?;
+        decoder.finish()?;
+        Ok(header)
+    }
+
+    pub fn id(&self) -> Hash {
+        Hash(hash(&self.to_bytes()))
     }
-    if POW_LEADING_ZEROS % 2 != 0 {pow_target[POW_LEADING_ZEROS/2] = 0x0f};
-    pow_target
-}
 
-fn reward_coinbase(prev_block:&Block) -> BTreeMap<Address, u128> {
-    let winner_balance = prev_block.balance_of(&prev_block.reward_addr);
-    let mut balances = prev_block.balances.clone();
-    let new_winner_balance = match winner_balance.checked_add(prev_block.coinbase_reward as u128) {
-        Some(bal) => bal,
-        None => u128::MAX
-    };
-    balances.insert(prev_block.reward_addr.clone(), new_winner_balance);
-    balances
+    /** Compares the header hash against the target as 256 bit big-endian numbers. */
+    pub fn has_valid_proof(&self) -> bool {
+        self.id() <= self.pow_target
+    }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Block {
+    pub header:BlockHeader,
+    /** Mints `coinbase_reward` plus this block's fees for `reward_addr`. Always the first transactions_root leaf. */
+    pub coinbase:Transaction,
+    pub transactions:BTreeMap<Hash, Transaction>,
+    /** Starting balances. Only the genesis block has any. */
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub allocations:BTreeMap<Address, u128>,
+    /** Accounts as of the end of this block, committed to by `header.state_root`.
+        It isn't sent or stored with the block; `rerun` rebuilds it from the parent's.
+     */
+    #[serde(skip)]
+    pub state:AccountState
+}
 impl Block {
-    pub fn new (reward_addr:Address, prev_block:&Block) -> Self {
-        Block {
-            reward_addr,
-            prev_block_hash: prev_block.id(),
-            chain_length: prev_block.chain_length+1,
-            balances: reward_coinbase(prev_block),
-            next_nonce: prev_block.next_nonce.clone(),
-            ..Default::default()
-        }
-    }
-
-    pub fn add_transaction(&mut self, tx:Transaction) -> bool {
-        if self.transactions.contains_key::<Hash>(&tx.id()) {println!("duplicate tx"); false;}
-        else if tx.sig.is_none() {println!("No sig"); false;}
-        else if !tx.valid_signature() {println!("Invalid Sig"); false;}
-        else if !tx.sufficient_funds(self) {println!("Insufficient Funds"); false;}
-
-        let nonce = match self.next_nonce.get_mut(&tx.from){
-            Some(nonce) => nonce,
-            None => {
-                self.next_nonce.insert(tx.from.clone(), 0);
-                self.next_nonce.get_mut(&tx.from).unwrap()
+    // the fields every block fills in from its chain and parent, left blank. Only `Blockchain`
+    // knows what they should be, so blocks are made through `make_genesis` and `make_block`
+    pub(crate) fn empty() -> Self {
+        let mut block = Block {
+            header: BlockHeader {
+                prev_block_hash:Hash(vec![0;32]),
+                transactions_root:Hash(vec![0;32]),
+                state_root:Hash(vec![0;32]),
+                reward_addr:Address::coinbase(),
+                coinbase_reward:0,
+                coinbase_maturity:0,
+                timestamp:now(),
+                pow_target:Hash(vec![0xff;32]),
+                proof:0,
+                chain_length:0
             },
+            coinbase:Transaction::coinbase(0, Address::coinbase(), 0),
+            transactions:BTreeMap::new(),
+            allocations:BTreeMap::new(),
+            state:AccountState::new()
         };
+        block.update_roots();
+        block
+    }
+
+    /** A child of `prev_block` paying `reward_addr`, still without its reward and target. See `Blockchain::make_block`. */
+    pub(crate) fn new(reward_addr:Address, prev_block:&Block) -> Result<Self, ValidationError> {
+        let mut block = Block::empty();
+        block.header.reward_addr = reward_addr;
+        block.header.prev_block_hash = prev_block.id();
+        block.header.chain_length = prev_block.header.chain_length+1;
+        block.header.coinbase_maturity = prev_block.header.coinbase_maturity;
+        block.apply_coinbases(prev_block)?;
+        block.update_roots();
+        Ok(block)
+    }
+
+    /** Starts from `prev_block`'s state, queues its coinbase, and credits every payout
+        minted at least `coinbase_maturity` blocks before this one.
+        Errors if a payout would push a balance past what a u128 holds.
+     */
+    fn apply_coinbases(&mut self, prev_block:&Block) -> Result<(), ValidationError> {
+        self.state = prev_block.state.clone();
+        self.state.chain_id = prev_block.chain_id();
+        //genesis mints nothing, and an empty payout shouldn't open an account
+        if let Some((address, amount)) = prev_block.coinbase.outputs.first().filter(|(_, amount)| *amount > 0) {
+            self.state.immature.insert(prev_block.header.chain_length, (address.clone(), *amount));
+        }
+        let height = self.header.chain_length;
+        let maturity = self.header.coinbase_maturity;
+        let matured:Vec<u32> = self.state.immature.keys()
+            .take_while(|minted| minted.saturating_add(maturity) <= height)
+            .copied()
+            .collect();
+        for minted in matured {
+            let (address, amount) = self.state.immature.remove(&minted).unwrap();
+            let balance = self.state.balance_of(&address).checked_add(amount)
+                .ok_or_else(|| ValidationError::BalanceOverflow(address.clone()))?;
+            self.state.set_balance(&address, balance)?;
+        }
+        Ok(())
+    }
+
+    /** The coinbase this block should carry given its header and transactions. */
+    pub fn expected_coinbase(&self) -> Transaction {
+        let amount = self.header.coinbase_reward.saturating_add(self.total_rewards());
+        Transaction::coinbase(self.header.chain_length, self.header.reward_addr.clone(), amount)
+    }
+
+    pub fn add_transaction(&mut self, tx:Transaction) -> Result<(), ValidationError> {
+        if self.transactions.contains_key::<Hash>(&tx.id()) {return Err(ValidationError::DuplicateTx(tx.id()));}
+        tx.validate(self)?;
+
+        let nonce = self.nonce_of(&tx.from);
         //replayed transaction
-        if tx.nonce < *nonce {println!("replayed tx"); false;}
+        if tx.nonce < nonce {return Err(ValidationError::ReplayedNonce { expected: nonce, got: tx.nonce });}
         //out of order tx
-        else if tx.nonce > *nonce {println!("out of order tx");return false;}
-        else {*nonce += 1}
-
-        let balance = match self.balances.get_mut(&tx.from){
-            Some(balance) => balance,
-            None => {
-                self.balances.insert(tx.from.clone(), 0);
-                self.balances.get_mut(&tx.from).unwrap()
-            },
-        };
-        *balance -= tx.total_output();
+        if tx.nonce > nonce {return Err(ValidationError::FutureNonce { expected: nonce, got: tx.nonce });}
+        let next_nonce = nonce.checked_add(1).ok_or_else(|| ValidationError::NonceOverflow(tx.from.clone()))?;
 
-        for i in 0..tx.outputs.len() {
-            let old_balance = match self.balances.get_mut(&tx.outputs[i].0){
-                Some(balance) => balance,
-                None => {
-                    self.balances.insert(tx.outputs[i].0.clone(), 0);
-                    self.balances.get_mut(&tx.outputs[i].0).unwrap()
-                },
-            };
-            *old_balance += tx.outputs[i].1;
+        //work on a copy so a tx that fails halfway leaves no trace. Copies share all their nodes.
+        let mut state = self.state.clone();
+        state.set_nonce(&tx.from, next_nonce)?;
+        let required = tx.total_output()?;
+        let available = state.balance_of(&tx.from);
+        let remaining = available.checked_sub(required)
+            .ok_or_else(|| ValidationError::InsufficientFunds { address: tx.from.clone(), available, required })?;
+        state.set_balance(&tx.from, remaining)?;
+
+        for (address, amount) in &tx.outputs {
+            let balance = state.balance_of(address).checked_add(*amount)
+                .ok_or_else(|| ValidationError::BalanceOverflow(address.clone()))?;
+            state.set_balance(address, balance)?;
         }
 
+        self.state = state;
         self.transactions.insert(tx.id(), tx);
+        self.update_roots();
 
-        return true;
+        Ok(())
     }
 
-    pub fn rerun(&mut self, prev_block:&Block) -> bool {
-        self.balances = reward_coinbase(prev_block);
-        self.next_nonce = prev_block.next_nonce.clone();
+    /** Errors unless this block sits exactly one above `prev_block`. Rewards and coinbase maturity
+        are keyed off the height, so it can't be left to the miner.
+     */
+    pub fn check_height(&self, prev_block:&Block) -> Result<(), ValidationError> {
+        let expected = prev_block.header.chain_length.checked_add(1);
+        if expected != Some(self.header.chain_length) {
+            return Err(ValidationError::WrongHeight {
+                block_id: self.id(),
+                expected: expected.unwrap_or(u32::MAX),
+                got: self.header.chain_length
+            });
+        }
+        Ok(())
+    }
+
+    /** Replays the transactions on top of `prev_block` and checks the header roots still match. */
+    pub fn rerun(&mut self, prev_block:&Block) -> Result<(), ValidationError> {
+        self.check_height(prev_block)?;
+        let claimed_header = self.header.clone();
+        let claimed_coinbase = self.coinbase.clone();
+        self.apply_coinbases(prev_block)?;
         let txs = self.transactions.clone();
         self.transactions = BTreeMap::new();
 
@@ -123,43 +226,145 @@ impl Block {
         let mut txs_sorted_by_nonce:Vec<Transaction> = txs.into_values().collect();
         txs_sorted_by_nonce.sort_by_key(|tx| tx.nonce);
         for tx in txs_sorted_by_nonce {
-            let success = self.add_transaction(tx);
-            if !success {return false}
+            let tx_id = tx.id();
+            self.add_transaction(tx).map_err(|reason| ValidationError::BadTransaction { tx_id, reason: Box::new(reason) })?;
+        }
+        self.update_roots();
+        if self.coinbase.id() != claimed_coinbase.id() {
+            return Err(ValidationError::BadCoinbase(claimed_header.id()));
         }
-        true
+        self.check_supply(prev_block)?;
+        if self.header != claimed_header {
+            return Err(ValidationError::RootMismatch(claimed_header.id()));
+        }
+        Ok(())
+    }
+
+    /** Ids of the coinbase followed by every other transaction, in the order the transactions root commits to them. */
+    pub fn tx_ids(&self) -> Vec<Hash> {
+        let mut tx_ids = vec![self.coinbase.id()];
+        tx_ids.extend(self.transactions.keys().cloned());
+        tx_ids
+    }
+
+    /** Leaves of the transactions root: the `witness_hash` of each transaction in `tx_ids` order,
+        so the block id covers signatures too.
+     */
+    pub fn tx_leaves(&self) -> Vec<Hash> {
+        let mut leaves = vec![self.coinbase.witness_hash()];
+        leaves.extend(self.transactions.values().map(|tx| tx.witness_hash()));
+        leaves
+    }
+
+    pub fn transactions_root(&self) -> Hash {
+        merkle_root(&self.tx_leaves())
+    }
+
+    /** Proves the transaction `tx_id` is committed to by `header.transactions_root`.
+        Check it with `verify_merkle_proof` against that transaction's `witness_hash`.
+     */
+    pub fn merkle_proof(&self, tx_id:&Hash) -> Option<MerkleProof> {
+        let index = self.tx_ids().iter().position(|id| id == tx_id)?;
+        merkle_proof(&self.tx_leaves(), index)
+    }
+
+    pub fn state_root(&self) -> Hash {
+        self.state.root()
+    }
+
+    /** Proves `address`'s balance and nonce, or that it has no account, against `header.state_root`.
+        Check it with `verify_account_proof`.
+     */
+    pub fn account_proof(&self, address:&Address) -> AccountProof {
+        self.state.prove(address)
     }
 
-    pub fn total_rewards(&self) -> u32 {
-        let mut total = 0;
-        for (_, tx) in &self.transactions {
-            total+=tx.fee
+    /** Rebuilds the coinbase and recomputes the header roots after the block changed. */
+    pub fn update_roots(&mut self) {
+        self.coinbase = self.expected_coinbase();
+        self.header.transactions_root = self.transactions_root();
+        self.header.state_root = self.state_root();
+    }
+
+    /** Fees collected by this block. They are paid to `reward_addr` through the coinbase. */
+    pub fn total_rewards(&self) -> u128 {
+        let mut total:u128 = 0;
+        for tx in self.transactions.values() {
+            total+=tx.fee as u128
         }
         total
     }
 
+    /** All spendable gold held in accounts. */
+    pub fn total_supply(&self) -> u128 {
+        self.state.total_balance()
+    }
+
+    /** Gold in accounts plus coinbase payouts that haven't matured, including this block's own. */
+    pub fn accounted_supply(&self) -> u128 {
+        let coinbase:u128 = self.coinbase.outputs.iter().map(|(_, amount)| *amount).sum();
+        self.state.immature.values()
+            .fold(self.total_supply(), |total, (_, amount)| total.saturating_add(*amount))
+            .saturating_add(coinbase)
+    }
+
+    /** The only gold a block may add on top of its parent's is its own coinbase reward.
+        Fees just move from senders to the miner.
+     */
+    pub fn check_supply(&self, prev_block:&Block) -> Result<(), ValidationError> {
+        let expected = prev_block.accounted_supply().saturating_add(self.header.coinbase_reward);
+        let actual = self.accounted_supply();
+        if expected != actual {
+            return Err(ValidationError::SupplyMismatch { block_id: self.id(), expected, actual });
+        }
+        Ok(())
+    }
+
     pub fn contains(&self, tx_id:&Hash) -> bool {
         self.transactions.contains_key::<Hash>(tx_id)
     }
 
     pub fn balance_of(&self, address:&Address) -> u128 {
-        match self.balances.get(address){
-            Some(bal) => bal.clone(),
-            None => 0
+        self.state.balance_of(address)
+    }
+
+    /** The nonce `address`'s next transaction must use. */
+    pub fn nonce_of(&self, address:&Address) -> u128 {
+        self.state.nonce_of(address)
+    }
+
+    /** Coinbase payouts to `address` that aren't spendable yet. */
+    pub fn immature_balance_of(&self, address:&Address) -> u128 {
+        self.state.immature.values()
+            .filter(|(owner, _)| owner == address)
+            .fold(0u128, |total, (_, amount)| total.saturating_add(*amount))
+    }
+
+    /** Writes the addresses in this block with the prefix `hrp`, as `Transaction::set_network` does.
+        The state is left alone, it picks the prefix up from the transactions the next block reruns.
+     */
+    pub fn set_network(&mut self, hrp:&str) {
+        self.header.reward_addr = self.header.reward_addr.clone().on_network(hrp);
+        self.coinbase.set_network(hrp);
+        for tx in self.transactions.values_mut() {
+            tx.set_network(hrp);
         }
+        self.allocations = std::mem::take(&mut self.allocations).into_iter()
+            .map(|(address, balance)| (address.on_network(hrp), balance))
+            .collect();
+    }
+
+    /** Id of this chain's genesis block. Transactions are signed for it. */
+    pub fn chain_id(&self) -> Hash {
+        if self.is_genesis() { self.id() } else { self.state.chain_id.clone() }
     }
 
     pub fn is_genesis(&self) -> bool {
-        self.chain_length == 0
+        self.header.chain_length == 0
     }
 
     pub fn has_valid_proof(&self) -> bool {
-        //self.hash_val() < self.pow_target
-        let hash = self.id();
-        for i in 0..hash.len() {
-            if self.pow_target[i] > 0x0f {return true;}
-            if hash[i] > self.pow_target[i] {return false;}
-        }
-        true
+        self.header.has_valid_proof()
     }
 
     /*pub fn mine(&mut self) -> u128 {
@@ -173,7 +378,172 @@ impl Block {
         serde_json::to_string(self).unwrap()
     }
 
+    /** Inverse of `serialize`. Decoding then re-encoding gives back the same `id()`.
+        Only a genesis block comes back with its state; any other needs `rerun` on its parent.
+     */
+    pub fn from_json(json:&str) -> serde_json::Result<Self> {
+        let block:Block = serde_json::from_str(json)?;
+        for (id, tx) in &block.transactions {
+            if *id != tx.id() {
+                return Err(de::Error::custom(format!("tx keyed as {} has id {}", id.as_hex(), tx.id().as_hex())));
+            }
+        }
+        block.check_decoded().map_err(de::Error::custom)
+    }
+
+    /** Canonical wire and storage format. State isn't included, the same as with `serialize`. */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::versioned();
+        self.header.encode(&mut encoder);
+        self.coinbase.encode(&mut encoder);
+        encoder.count(self.transactions.len());
+        for tx in self.transactions.values() {
+            tx.encode(&mut encoder);
+        }
+        encoder.count(self.allocations.len());
+        for (address, balance) in &self.allocations {
+            encoder.address(address);
+            encoder.u128(*balance);
+        }
+        encoder.finish()
+    }
+
+    /** Inverse of `to_bytes`. As with `from_json`, only a genesis block comes back with its state. */
+    pub fn from_bytes(bytes:&[u8]) -> Result<Self, DecodeError> {
+        let mut decoder = Decoder::new(bytes);
+        decoder.version()?;
+        let header = BlockHeader::decode(&mut decoder)?;
+        let coinbase = Transaction::decode(&mut decoder)?;
+        let mut transactions = BTreeMap::new();
+        for _ in 0..decoder.count()? {
+            let tx = Transaction::decode(&mut decoder)?;
+            transactions.insert(tx.id(), tx);
+        }
+        let mut allocations = BTreeMap::new();
+        for _ in 0..decoder.count()? {
+            allocations.insert(decoder.address()?, decoder.u128()?);
+        }
+        decoder.finish()?;
+        let block = Block { header, coinbase, transactions, allocations, state: AccountState::new() };
+        block.check_decoded().map_err(DecodeError::Invalid)
+    }
+
+    // checks the roots a freshly decoded block can be checked against on its own
+    fn check_decoded(mut self) -> Result<Self, String> {
+        if self.header.transactions_root != self.transactions_root() {
+            return Err("transactions root does not match the block body".to_string());
+        }
+        if self.is_genesis() {
+            self.state = AccountState::from_balances(&self.allocations).map_err(|err| err.to_string())?;
+            if self.header.state_root != self.state_root() {
+                return Err("genesis state root does not match its allocations".to_string());
+            }
+        }
+        else if !self.allocations.is_empty() {
+            return Err("only the genesis block can have allocations".to_string());
+        }
+        Ok(self)
+    }
+
     pub fn id(&self) -> Hash {
-        Hash(hash(self.serialize().as_bytes()).to_vec())
+        self.header.id()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use crate::{calc_address, Address, Block, Blockchain, Client, MemoryBlockStore, Transaction, ValidationError};
+
+    fn chain() -> Blockchain {
+        Blockchain::new().with_pow_leading_zeros(0).with_retarget_interval(0)
+    }
+
+    fn payer() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap()
+    }
+
+    fn payment(keypair:&Ed25519KeyPair, genesis:&Block, nonce:u128, amount:u128) -> Transaction {
+        let public_key = keypair.public_key().as_ref();
+        let mut tx = Transaction::new(calc_address(public_key), nonce, public_key.to_vec(), vec![(Address::from_public_key(&[2; 32]), amount)], 1, String::new());
+        tx.sign(keypair, &genesis.chain_id());
+        tx
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn says_why_a_transaction_is_rejected() {
+        let chain = chain();
+        let payer = payer();
+        let from = calc_address(payer.public_key().as_ref());
+        let genesis = chain.make_genesis(BTreeMap::from([(from.clone(), 100)]));
+        let mut block = chain.make_block(Address::from_public_key(&[3; 32]), &genesis, &MemoryBlockStore::new()).unwrap();
+        let mut unsigned = payment(&payer, &genesis, 0, 10);
+        unsigned.sig = None;
+        assert_eq!(block.add_transaction(unsigned), Err(ValidationError::MissingSignature));
+        assert_eq!(block.add_transaction(payment(&payer, &genesis, 0, 100)),
+            Err(ValidationError::InsufficientFunds { address: from.clone(), available: 100, required: 101 }));
+        assert_eq!(block.add_transaction(payment(&payer, &genesis, 1, 10)), Err(ValidationError::FutureNonce { expected: 0, got: 1 }));
+        let tx = payment(&payer, &genesis, 0, 10);
+        block.add_transaction(tx.clone()).unwrap();
+        assert_eq!(block.add_transaction(tx.clone()), Err(ValidationError::DuplicateTx(tx.id())));
+        assert_eq!(block.add_transaction(payment(&payer, &genesis, 0, 20)), Err(ValidationError::ReplayedNonce { expected: 1, got: 0 }));
+        //the rejected ones left no trace
+        assert_eq!((block.balance_of(&from), block.nonce_of(&from)), (89, 1));
+    }
+
+    #[test]
+    fn rejects_a_block_whose_body_does_not_add_up() {
+        let chain = chain();
+        let payer = payer();
+        let genesis = chain.make_genesis(BTreeMap::from([(calc_address(payer.public_key().as_ref()), 100)]));
+        let mut client = Client::new("client".to_string(), chain.clone(), Some(genesis.clone()), None);
+        let mut block = chain.make_block(client.address(), &genesis, &*client.blocks).unwrap();
+        block.add_transaction(payment(&payer, &genesis, 0, 10)).unwrap();
+
+        let mut wrong_root = block.clone();
+        wrong_root.header.state_root = genesis.header.state_root.clone();
+        assert_eq!(client.receive_block(wrong_root.clone()).err(), Some(ValidationError::RootMismatch(wrong_root.id())));
+
+        //slipped into the body after the state was worked out
+        let mut overspent = block.clone();
+        let tx = payment(&payer, &genesis, 1, 95);
+        overspent.transactions.insert(tx.id(), tx.clone());
+        overspent.update_roots();
+        let reason = Box::new(ValidationError::InsufficientFunds { address: tx.from.clone(), available: 89, required: 96 });
+        assert_eq!(client.receive_block(overspent).err(), Some(ValidationError::BadTransaction { tx_id: tx.id(), reason }));
+
+        assert!(client.receive_block(block).is_ok());
+    }
+
+
+    #[test]
+    fn matured_coinbase_cannot_overflow_a_balance() {
+        let chain = Blockchain::new().with_pow_leading_zeros(0).with_coinbase_reward(10).with_coinbase_maturity(1);
+        let store = MemoryBlockStore::new();
+        let miner = Address::from_public_key(&[1; 32]);
+        let genesis = chain.make_genesis(BTreeMap::from([(miner.clone(), u128::MAX - 5)]));
+        let block = chain.make_block(miner.clone(), &genesis, &store).unwrap();
+        assert_eq!(block.coinbase.outputs, vec![(miner.clone(), 10)]);
+        //its reward matures in the next block, and 5 more is all the balance has room for
+        assert_eq!(chain.make_block(miner.clone(), &block, &store).err(), Some(ValidationError::BalanceOverflow(miner)));
+    }
+
+    #[test]
+    fn coinbase_is_spendable_only_once_mature() {
+        let chain = chain().with_coinbase_reward(10).with_coinbase_maturity(2);
+        let store = MemoryBlockStore::new();
+        let miner = payer();
+        let reward_addr = calc_address(miner.public_key().as_ref());
+        let genesis = chain.make_genesis(BTreeMap::new());
+        let first = chain.make_block(reward_addr.clone(), &genesis, &store).unwrap();
+        assert_eq!(first.coinbase.outputs, vec![(reward_addr.clone(), 10)]);
+        let mut second = chain.make_block(reward_addr.clone(), &first, &store).unwrap();
+        assert_eq!((second.balance_of(&reward_addr), second.immature_balance_of(&reward_addr)), (0, 10));
+        assert_eq!(second.add_transaction(payment(&miner, &genesis, 0, 5)),
+            Err(ValidationError::InsufficientFunds { address: reward_addr.clone(), available: 0, required: 6 }));
+        let mut third = chain.make_block(reward_addr.clone(), &second, &store).unwrap();
+        assert_eq!((third.balance_of(&reward_addr), third.immature_balance_of(&reward_addr)), (10, 10));
+        third.add_transaction(payment(&miner, &genesis, 0, 5)).unwrap();
+        assert_eq!(third.accounted_supply(), 30);
+    }
+}
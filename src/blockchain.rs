@@ -1,28 +1,61 @@
 use std::collections::btree_map::BTreeMap;
-use crate::{Address, Block, Transaction};
+use std::fs;
+use std::io;
+use serde::{Deserialize, Serialize};
+use crate::{now, AccountState, Address, AddressError, Block, BlockStore, Hash, Issuance, Transaction, ValidationError, HASH_LEN, MAINNET_HRP};
 
 pub const DEFAULT_MINING_ROUNDS:usize = 3000;
+pub const DEFAULT_MINING_WORKERS:usize = 1;
 pub const DEFAULT_FEE:u32 = 1;
-pub const COINBASE_REWARD:u16 = 25;
+pub const COINBASE_REWARD:u128 = 25;
+pub const HALVING_INTERVAL:u32 = 100_000;
+pub const COINBASE_MATURITY:u32 = 5;
 pub const CONFIRMED_DEPTH:u8 = 2;
+pub const MAX_PENDING_BLOCKS:usize = 256;
 pub const POW_LEADING_ZEROS:usize = 3;
+/** Hex digits in a hash, so a target of all zeros. */
+pub const MAX_POW_LEADING_ZEROS:usize = 2 * HASH_LEN;
+pub const RETARGET_INTERVAL:u32 = 10;
+pub const TARGET_BLOCK_TIME:u128 = 1000;
+pub const MAX_FUTURE_DRIFT:u128 = 60_000;
+// a retarget can move difficulty by at most this factor either way
+const MAX_RETARGET_FACTOR:u128 = 4;
+const RETARGET_PRECISION:u64 = 1024;
+// a block's timestamp can't be earlier than the median of this many blocks before it
+const MEDIAN_TIME_SPAN:usize = 11;
 
 
-
+/** Consensus parameters for a single chain.
+    Every Client and Miner holds one, so several networks can run in one process.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
 pub struct Blockchain {
     pow_leading_zeros:usize,
-    coinbase_reward:u16,
+    issuance:Issuance,
+    max_supply:Option<u128>,
+    coinbase_maturity:u32,
     default_tx_fee:u32,
-    confirmed_depth:u8
+    confirmed_depth:u8,
+    retarget_interval:u32,
+    target_block_time:u128,
+    max_future_drift:u128,
+    address_hrp:String
 }
 
 impl Default for Blockchain {
     fn default() -> Self {
         Blockchain {
             pow_leading_zeros: POW_LEADING_ZEROS,
-            coinbase_reward: COINBASE_REWARD,
+            issuance: Issuance::default(),
+            max_supply: None,
+            coinbase_maturity: COINBASE_MATURITY,
             default_tx_fee: DEFAULT_FEE,
-            confirmed_depth: CONFIRMED_DEPTH
+            confirmed_depth: CONFIRMED_DEPTH,
+            retarget_interval: RETARGET_INTERVAL,
+            target_block_time: TARGET_BLOCK_TIME,
+            max_future_drift: MAX_FUTURE_DRIFT,
+            address_hrp: MAINNET_HRP.to_string()
         }
     }
 }
@@ -35,20 +68,424 @@ impl Blockchain {
         }
     }
 
-    pub fn make_genesis(starting_balances:BTreeMap<Address, u128>) -> Block {
-        Block {
-            balances: starting_balances,
-            ..Default::default()
+    /** Reads a JSON chain spec. Missing fields fall back to the defaults. */
+    pub fn from_json(spec:&str) -> serde_json::Result<Self> {
+        let blockchain:Blockchain = serde_json::from_str(spec)?;
+        if !Address::is_valid_hrp(&blockchain.address_hrp) {
+            return Err(serde::de::Error::custom(format!("invalid address_hrp {}", blockchain.address_hrp)));
+        }
+        if blockchain.pow_leading_zeros > MAX_POW_LEADING_ZEROS {
+            return Err(serde::de::Error::custom(format!("pow_leading_zeros {} is more than a hash has digits", blockchain.pow_leading_zeros)));
+        }
+        Ok(blockchain)
+    }
+
+    /** `from_json` on the contents of `path`. A spec that doesn't parse is an `InvalidData` error. */
+    pub fn from_spec_file(path:&str) -> io::Result<Self> {
+        let spec = fs::read_to_string(path)?;
+        Ok(Blockchain::from_json(&spec)?)
+    }
+
+    /** Hex digits every block id must start with at genesis. At most `MAX_POW_LEADING_ZEROS`. */
+    pub fn with_pow_leading_zeros(mut self, pow_leading_zeros:usize) -> Self {
+        self.pow_leading_zeros = pow_leading_zeros.min(MAX_POW_LEADING_ZEROS);
+        self
+    }
+
+    pub fn with_issuance(mut self, issuance:Issuance) -> Self {
+        self.issuance = issuance;
+        self
+    }
+
+    /** Shorthand for a fixed reward at every height. */
+    pub fn with_coinbase_reward(mut self, coinbase_reward:u128) -> Self {
+        self.issuance = Issuance::Fixed { reward: coinbase_reward };
+        self
+    }
+
+    /** Caps the gold coinbases can ever mint. Genesis balances don't count towards it. */
+    pub fn with_max_supply(mut self, max_supply:Option<u128>) -> Self {
+        self.max_supply = max_supply;
+        self
+    }
+
+    /** How many blocks after its own a coinbase payout becomes spendable. */
+    pub fn with_coinbase_maturity(mut self, coinbase_maturity:u32) -> Self {
+        self.coinbase_maturity = coinbase_maturity;
+        self
+    }
+
+    pub fn with_default_tx_fee(mut self, default_tx_fee:u32) -> Self {
+        self.default_tx_fee = default_tx_fee;
+        self
+    }
+
+    pub fn with_confirmed_depth(mut self, confirmed_depth:u8) -> Self {
+        self.confirmed_depth = confirmed_depth;
+        self
+    }
+
+    /** Recompute the target every `retarget_interval` blocks. 0 keeps the target fixed. */
+    pub fn with_retarget_interval(mut self, retarget_interval:u32) -> Self {
+        self.retarget_interval = retarget_interval;
+        self
+    }
+
+    /** Desired milliseconds between blocks. */
+    pub fn with_target_block_time(mut self, target_block_time:u128) -> Self {
+        self.target_block_time = target_block_time;
+        self
+    }
+
+    /** How many milliseconds ahead of our clock a block's timestamp may be. */
+    pub fn with_max_future_drift(mut self, max_future_drift:u128) -> Self {
+        self.max_future_drift = max_future_drift;
+        self
+    }
+
+    /** Prefix of this network's addresses, e.g. `TESTNET_HRP` so test gold can't be sent to mainnet addresses. */
+    pub fn with_address_hrp(mut self, address_hrp:&str) -> Self {
+        assert!(Address::is_valid_hrp(address_hrp), "invalid address prefix {}", address_hrp);
+        self.address_hrp = address_hrp.to_string();
+        self
+    }
+
+    pub fn address_hrp(&self) -> &str {
+        &self.address_hrp
+    }
+
+    /** `address` as users of this network write it. */
+    pub fn format_address(&self, address:&Address) -> String {
+        address.encode(&self.address_hrp)
+    }
+
+    /** Parses an address written for this network, rejecting ones with another network's prefix. */
+    pub fn parse_address(&self, address:&str) -> Result<Address, AddressError> {
+        Address::decode(address, &self.address_hrp)
+    }
+
+    pub fn max_future_drift(&self) -> u128 {
+        self.max_future_drift
+    }
+
+    pub fn retarget_interval(&self) -> u32 {
+        self.retarget_interval
+    }
+
+    pub fn target_block_time(&self) -> u128 {
+        self.target_block_time
+    }
+
+    pub fn pow_leading_zeros(&self) -> usize {
+        self.pow_leading_zeros
+    }
+
+    pub fn issuance(&self) -> &Issuance {
+        &self.issuance
+    }
+
+    pub fn max_supply(&self) -> Option<u128> {
+        self.max_supply
+    }
+
+    /** Total the coinbases at heights `1..=height` may mint, cap included.
+        Genesis mints nothing, its allocations are the chain's starting supply.
+     */
+    pub fn issued_through(&self, height:u32) -> u128 {
+        let issued = self.issuance.issued_through(height).saturating_sub(self.issuance.reward_at(0));
+        match self.max_supply {
+            Some(max_supply) => issued.min(max_supply),
+            None => issued
+        }
+    }
+
+    /** What the coinbase at `height` mints. 0 for genesis, and once the cap is reached. */
+    pub fn reward_at(&self, height:u32) -> u128 {
+        let before = if height == 0 { 0 } else { self.issued_through(height - 1) };
+        self.issued_through(height) - before
+    }
+
+    pub fn coinbase_maturity(&self) -> u32 {
+        self.coinbase_maturity
+    }
+
+    pub fn default_tx_fee(&self) -> u32 {
+        self.default_tx_fee
+    }
+
+    pub fn confirmed_depth(&self) -> u8 {
+        self.confirmed_depth
+    }
+
+    /** The genesis target, and the easiest target retargeting will ever allow. */
+    pub fn pow_target(&self) -> Hash {
+        let mut pow_target:Hash = Hash(vec![0xff;32]);
+        for i in 0..self.pow_leading_zeros/2 {
+            pow_target[i] = 0x00;
+        }
+        if self.pow_leading_zeros % 2 != 0 {pow_target[self.pow_leading_zeros/2] = 0x0f};
+        pow_target
+    }
+
+    /** The target a child of `prev_block` must be mined against.
+        Every `retarget_interval` blocks the parent's target is scaled by how long the
+        last window actually took compared to `target_block_time`.
+     */
+    pub fn next_target(&self, prev_block:&Block, blocks:&dyn BlockStore) -> Hash {
+        let height = prev_block.header.chain_length + 1;
+        if self.retarget_interval == 0 || !height.is_multiple_of(self.retarget_interval) {
+            return prev_block.header.pow_target.clone();
+        }
+        let mut window_start = prev_block.clone();
+        let mut intervals:u128 = 0;
+        while intervals < self.retarget_interval as u128 && !window_start.is_genesis() {
+            window_start = match blocks.get(&window_start.header.prev_block_hash) {
+                Some(block) => block,
+                None => break
+            };
+            intervals += 1;
         }
+        if intervals == 0 {
+            return prev_block.header.pow_target.clone();
+        }
+        let expected = (intervals * self.target_block_time).max(1);
+        let actual = prev_block.header.timestamp.saturating_sub(window_start.header.timestamp)
+            .clamp(expected / MAX_RETARGET_FACTOR, expected * MAX_RETARGET_FACTOR);
+        let factor = (actual * RETARGET_PRECISION as u128 / expected) as u64;
+        //dividing first keeps an easy target from overflowing, and only loses its lowest bits
+        let scaled = mul_target(&div_target(&prev_block.header.pow_target, RETARGET_PRECISION), factor);
+        scaled.min(self.pow_target())
     }
 
-    //fn deserialize_block
+    /** Median timestamp of `prev_block` and the blocks before it, `MEDIAN_TIME_SPAN` in all.
+        A child's timestamp can't be earlier than this.
+     */
+    pub fn median_time_past(&self, prev_block:&Block, blocks:&dyn BlockStore) -> u128 {
+        let mut timestamps = vec![prev_block.header.timestamp];
+        let mut block = prev_block.clone();
+        while timestamps.len() < MEDIAN_TIME_SPAN && !block.is_genesis() {
+            block = match blocks.get(&block.header.prev_block_hash) {
+                Some(block) => block,
+                None => break
+            };
+            timestamps.push(block.header.timestamp);
+        }
+        timestamps.sort();
+        timestamps[timestamps.len() / 2]
+    }
 
-    pub fn make_block(reward_addr:Address, prev_block:&Block) -> Block {
-        Block::new(reward_addr, prev_block)
+    /** Retargeting trusts timestamps, so a child of `prev_block` may neither go back before
+        the median time past nor run more than `max_future_drift` ahead of our clock.
+     */
+    pub fn check_timestamp(&self, block:&Block, prev_block:&Block, blocks:&dyn BlockStore) -> Result<(), ValidationError> {
+        let median_time_past = self.median_time_past(prev_block, blocks);
+        if block.header.timestamp < median_time_past {
+            return Err(ValidationError::TimestampTooEarly { block_id: block.id(), median_time_past });
+        }
+        let limit = now().saturating_add(self.max_future_drift);
+        if block.header.timestamp > limit {
+            return Err(ValidationError::TimestampTooLate { block_id: block.id(), limit });
+        }
+        Ok(())
+    }
+
+    /** Checks the parts of a block that do not depend on its parent. */
+    pub fn follows_rules(&self, block:&Block) -> bool {
+        let target_ok = !block.is_genesis() || block.header.pow_target == self.pow_target();
+        //a reward paid to nowhere would count towards supply but could never be spent
+        let reward_addr_ok = block.is_genesis() || block.header.reward_addr.can_receive();
+        //only genesis allocations feed the state root, anywhere else they could be added without changing the id
+        let allocations_ok = block.is_genesis() || block.allocations.is_empty();
+        target_ok && reward_addr_ok && allocations_ok && block.header.coinbase_reward == self.reward_at(block.header.chain_length)
+            && block.header.coinbase_maturity == self.coinbase_maturity
+    }
+
+    /** Panics if `starting_balances` add up to more than a u128 holds. */
+    pub fn make_genesis(&self, starting_balances:BTreeMap<Address, u128>) -> Block {
+        let state = match AccountState::from_balances(&starting_balances) {
+            Ok(state) => state,
+            Err(err) => panic!("Genesis allocations don't fit: {}", err)
+        };
+        let mut block = Block {
+            state,
+            allocations: starting_balances,
+            ..Block::empty()
+        };
+        block.header.pow_target = self.pow_target();
+        block.header.coinbase_reward = self.reward_at(block.header.chain_length);
+        block.header.coinbase_maturity = self.coinbase_maturity;
+        block.update_roots();
+        block
+    }
+
+    /** The next block on top of `prev_block`, without transactions or proof yet.
+        Errors if crediting the coinbases that mature in it would overflow a balance.
+     */
+    pub fn make_block(&self, reward_addr:Address, prev_block:&Block, blocks:&dyn BlockStore) -> Result<Block, ValidationError> {
+        let mut block = Block::new(reward_addr, prev_block)?;
+        //keep our own block valid even if our clock is behind the chain's
+        block.header.timestamp = block.header.timestamp.max(self.median_time_past(prev_block, blocks));
+        block.header.pow_target = self.next_target(prev_block, blocks);
+        block.header.coinbase_reward = self.reward_at(block.header.chain_length);
+        block.update_roots();
+        Ok(block)
     }
 
     pub fn make_transaction(from:Address, nonce:u128, pubkey_bytes:Vec<u8>, outputs:Vec<(Address, u128)>, fee: u32, data: String) -> Transaction {
         Transaction::new(from, nonce, pubkey_bytes, outputs, fee, data)
     }
-}
\ No newline at end of file
+}
+
+// targets are 256 bit big-endian numbers
+fn mul_target(target:&Hash, factor:u64) -> Hash {
+    let mut result = vec![0u8; HASH_LEN];
+    let mut carry:u128 = 0;
+    for i in (0..HASH_LEN).rev() {
+        let product = target[i] as u128 * factor as u128 + carry;
+        result[i] = product as u8;
+        carry = product >> 8;
+    }
+    if carry > 0 {
+        return Hash(vec![0xff; HASH_LEN]);
+    }
+    Hash(result)
+}
+
+fn div_target(target:&Hash, divisor:u64) -> Hash {
+    let mut result = vec![0u8; HASH_LEN];
+    let mut remainder:u128 = 0;
+    for i in 0..HASH_LEN {
+        let current = (remainder << 8) | target[i] as u128;
+        result[i] = (current / divisor as u128) as u8;
+        remainder = current % divisor as u128;
+    }
+    Hash(result)
+}
+
+/** Expected number of hashes needed to meet `target`, i.e. 2^256 / (target + 1).
+    Only the top 64 significant bits of the target are used, which is plenty to rank chains.
+ */
+pub fn block_work(target:&Hash) -> u128 {
+    let leading_zeros = target.iter().take_while(|byte| **byte == 0).count();
+    let mut top = [0u8; 8];
+    for (i, byte) in target.iter().skip(leading_zeros).take(8).enumerate() {
+        top[i] = *byte;
+    }
+    // target ~= mantissa * 2^(8 * (HASH_LEN - leading_zeros - 8))
+    let mantissa = u64::from_be_bytes(top) as u128 + 1;
+    let exponent = 8 * (HASH_LEN as i32 - leading_zeros as i32 - 8);
+    // 2^256 / target ~= (2^128 / mantissa) * 2^(128 - exponent)
+    let work = u128::MAX / mantissa;
+    let shift = 128 - exponent;
+    if shift <= 0 {
+        (work >> (-shift).min(127)).max(1)
+    }
+    else if work.leading_zeros() as i32 >= shift {
+        work << shift
+    }
+    else {
+        u128::MAX
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io;
+    use crate::{now, Address, Block, Blockchain, Client, Hash, MemoryBlockStore, ValidationError};
+    use super::{div_target, MAX_POW_LEADING_ZEROS};
+
+    fn solved(mut block:Block) -> Block {
+        while !block.header.has_valid_proof() {
+            block.header.proof += 1;
+        }
+        block
+    }
+
+    // a child of the client's tip stamped `timestamp`
+    fn mined_at(client:&Client, timestamp:u128) -> Block {
+        let tip = client.last_block().unwrap();
+        let mut block = client.blockchain.make_block(client.address(), &tip, &*client.blocks).unwrap();
+        block.header.timestamp = timestamp;
+        solved(block)
+    }
+
+    #[test]
+    fn spec_needs_a_target_a_hash_can_meet() {
+        let chain = Blockchain::from_json(&format!("{{\"pow_leading_zeros\":{}}}", MAX_POW_LEADING_ZEROS)).unwrap();
+        assert!(chain.pow_target().iter().all(|byte| *byte == 0));
+        assert!(Blockchain::from_json(&format!("{{\"pow_leading_zeros\":{}}}", MAX_POW_LEADING_ZEROS + 1)).is_err());
+        assert_eq!(Blockchain::new().with_pow_leading_zeros(100), chain);
+        assert_eq!(Blockchain::from_spec_file("/nonexistent/chain.json").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn blocks_follow_their_own_chain() {
+        let chain = Blockchain::new().with_pow_leading_zeros(0).with_coinbase_reward(7).with_coinbase_maturity(1);
+        let store = MemoryBlockStore::new();
+        let genesis = chain.make_genesis(BTreeMap::new());
+        let block = chain.make_block(Address::from_public_key(&[1; 32]), &genesis, &store).unwrap();
+        assert_eq!(block.header.coinbase_reward, 7);
+        assert_eq!(block.header.coinbase_maturity, 1);
+        assert!(chain.follows_rules(&block));
+        assert!(!Blockchain::new().with_pow_leading_zeros(0).follows_rules(&block));
+    }
+
+    #[test]
+    fn retargets_from_block_times() {
+        let chain = Blockchain::new().with_pow_leading_zeros(1).with_retarget_interval(2).with_target_block_time(1000);
+        let genesis = chain.make_genesis(BTreeMap::new());
+        let start = genesis.header.timestamp;
+        let mut fast = Client::new("fast".to_string(), chain.clone(), Some(genesis.clone()), None);
+        let block = mined_at(&fast, start + 250);
+        fast.receive_block(block.clone()).unwrap();
+        //four times as fast as it should be, so four times as hard
+        let easiest = chain.pow_target();
+        let harder = chain.next_target(&block, &*fast.blocks);
+        assert!(harder <= div_target(&easiest, 4) && harder > div_target(&easiest, 5));
+
+        let mut slow = Client::new("slow".to_string(), chain.clone(), Some(genesis), None);
+        let block = mined_at(&slow, start + 4000);
+        slow.receive_block(block.clone()).unwrap();
+        //and never easier than the genesis target
+        assert_eq!(chain.next_target(&block, &*slow.blocks), easiest);
+
+        let mut wrong = mined_at(&fast, start + 500);
+        wrong.header.pow_target = easiest;
+        let wrong = solved(wrong);
+        assert_eq!(fast.receive_block(wrong.clone()).err(), Some(ValidationError::BadTarget(wrong.id())));
+        assert!(fast.receive_block(mined_at(&fast, start + 500)).is_ok());
+    }
+
+    #[test]
+    fn rejects_timestamps_out_of_bounds() {
+        let chain = Blockchain::new().with_pow_leading_zeros(1).with_retarget_interval(0).with_max_future_drift(60_000);
+        let genesis = chain.make_genesis(BTreeMap::new());
+        let mut client = Client::new("client".to_string(), chain, Some(genesis.clone()), None);
+        let early = mined_at(&client, genesis.header.timestamp - 1);
+        assert_eq!(client.receive_block(early.clone()).err(),
+            Some(ValidationError::TimestampTooEarly { block_id: early.id(), median_time_past: genesis.header.timestamp }));
+        let late = mined_at(&client, now() + 120_000);
+        assert!(matches!(client.receive_block(late).err(), Some(ValidationError::TimestampTooLate { .. })));
+        assert!(client.receive_block(mined_at(&client, genesis.header.timestamp)).is_ok());
+    }
+
+    #[test]
+    fn stops_minting_at_the_cap() {
+        let chain = Blockchain::new().with_pow_leading_zeros(0).with_retarget_interval(0).with_coinbase_reward(10).with_max_supply(Some(25));
+        assert_eq!([0, 1, 2, 3, 4].map(|height| chain.reward_at(height)), [0, 10, 10, 5, 0]);
+        assert_eq!(chain.issued_through(u32::MAX), 25);
+        let genesis = chain.make_genesis(BTreeMap::new());
+        let mut client = Client::new("client".to_string(), chain.clone(), Some(genesis), None);
+        for _ in 0..4 {
+            let block = mined_at(&client, now());
+            client.receive_block(block).unwrap();
+        }
+        assert_eq!(client.audit_supply(), Ok(25));
+        //a miner paying itself past the cap
+        let mut greedy = mined_at(&client, now());
+        greedy.header.coinbase_reward = 10;
+        greedy.update_roots();
+        assert_eq!(client.receive_block(greedy.clone()).err(), Some(ValidationError::RuleMismatch(greedy.id())));
+    }
+}
@@ -1,5 +1,6 @@
 use std::borrow::Borrow;
 use std::collections::BTreeMap;
+use std::iter;
 use std::{default, result};
 use std::error::Error;
 use std::fmt::format;
@@ -8,19 +9,32 @@ use std::ptr::null;
 use base64::encode as base64;
 use hex::{encode, decode};
 use ring::signature::{Ed25519KeyPair, KeyPair};
-use crate::{Address, Block, Blockchain, calc_address, generate_keypair, Hash, Transaction};
-use crate::blockchain::{CONFIRMED_DEPTH, DEFAULT_FEE};
+use crate::blockchain::MAX_PENDING_BLOCKS;
+use crate::{Address, Block, block_work, Blockchain, BlockStore, calc_address, generate_keypair, Hash, HdAccount, HdWallet, Keystore, MemoryBlockStore, MultisigPolicy, SigningRequest, Transaction, ValidationError, WalletError};
+
+/** A change of main chain. `disconnected` runs from the old tip back to the fork point,
+    `connected` from the fork point up to the new tip.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reorg {
+    pub disconnected:Vec<Hash>,
+    pub connected:Vec<Hash>
+}
 
 pub struct Client {
     pub keypair:Ed25519KeyPair,
     pub name: String,
+    pub blockchain: Blockchain,
     nonce: u128,
+    /** Txs prepared here to be signed elsewhere and not yet mined, by sender and nonce. */
+    prepared_requests: BTreeMap<(Address, u128), Transaction>,
     pending_outgoing_transactions: BTreeMap<Hash, Transaction>,
     pending_received_transactions: BTreeMap<Hash, Transaction>,
-    pub blocks:BTreeMap<Hash, Block>,
+    pub blocks:Box<dyn BlockStore>,
     last_confirmed_block_id: Option<Hash>,
     last_block_id: Option<Hash>,
-    pending_blocks: BTreeMap<Hash, Vec<Block>>
+    pending_blocks: BTreeMap<Hash, Vec<Block>>,
+    last_reorg: Option<Reorg>
 
 }
 
@@ -29,25 +43,26 @@ impl Default for Client {
         Client {
             keypair:generate_keypair(),
             name:String::from(""),
+            blockchain: Blockchain::default(),
             nonce: 0,
+            prepared_requests: BTreeMap::new(),
             pending_outgoing_transactions: BTreeMap::new(),
             pending_received_transactions: BTreeMap::new(),
-            blocks: BTreeMap::new(),
+            blocks: Box::new(MemoryBlockStore::new()),
             last_confirmed_block_id: None,
             last_block_id: None,
-            pending_blocks:BTreeMap::new()
+            pending_blocks:BTreeMap::new(),
+            last_reorg: None
         }
     }
 }
 
 impl Client {
-    pub fn new(name:String, starting_block:Option<Block>, keypair:Option<Ed25519KeyPair>) -> Self {
+    pub fn new(name:String, blockchain:Blockchain, starting_block:Option<Block>, keypair:Option<Ed25519KeyPair>) -> Self {
         let mut client = Client{
-            keypair: {
-                if keypair.is_some() {keypair.unwrap()}
-                else {generate_keypair()}
-            },
+            keypair: keypair.unwrap_or_else(generate_keypair),
             name,
+            blockchain,
             ..Default::default()
         };
         if starting_block.is_some() {
@@ -56,21 +71,55 @@ impl Client {
         client
     }
 
-    pub fn set_genesis(&mut self,starting_block: Block) {
+    /** Like `new`, with the signing key unlocked from `keystore`. */
+    pub fn from_keystore(name:String, blockchain:Blockchain, starting_block:Option<Block>, keystore:&Keystore, key_name:&str, passphrase:&str) -> Result<Self, WalletError> {
+        let keypair = keystore.unlock(key_name, passphrase)?;
+        Ok(Client::new(name, blockchain, starting_block, Some(keypair)))
+    }
+
+    /** Like `new`, signing with account `index` of an HD wallet. */
+    pub fn from_hd_account(name:String, blockchain:Blockchain, starting_block:Option<Block>, wallet:&HdWallet, index:u32) -> Self {
+        Client::new(name, blockchain, starting_block, Some(wallet.account(index)))
+    }
+
+    /** Builds a client on top of blocks that were stored earlier, restoring its chain tip.
+        An empty store still needs `set_genesis`.
+     */
+    pub fn with_store(name:String, blockchain:Blockchain, store:Box<dyn BlockStore>, keypair:Option<Ed25519KeyPair>) -> Self {
+        let mut client = Client{
+            keypair: keypair.unwrap_or_else(generate_keypair),
+            name,
+            blockchain,
+            blocks: store,
+            ..Default::default()
+        };
+        if let Some(tip) = client.blocks.tip() {
+            client.last_block_id = Some(tip);
+            client.set_last_confirmed();
+            let tip = client.last_block().unwrap();
+            client.nonce = tip.nonce_of(&client.address());
+        }
+        client
+    }
+
+    pub fn set_genesis(&mut self, mut starting_block: Block) {
         if !self.last_block_id.is_none() {
             panic!("Trying to set_genesis on existing blockchain")
         }
         if !self.blocks.is_empty() {
             panic!("Trying to set_genesis on existing blockchain")
         }
-        self.blocks.insert(starting_block.id(), starting_block.clone());
+        starting_block.set_network(self.blockchain.address_hrp());
+        if let Err(err) = self.blocks.insert(starting_block.clone(), block_work(&starting_block.header.pow_target)) {
+            panic!("Unable to store genesis block: {}", err);
+        }
         self.last_block_id = Some(starting_block.id());
         self.last_confirmed_block_id = Some(starting_block.id());
     }
 
     pub fn last_block(&self) -> Option<Block> {
         if self.last_block_id.is_some() {
-            Some(self.blocks.get(&self.last_block_id.as_ref().unwrap()).unwrap().clone())
+            Some(self.blocks.get(self.last_block_id.as_ref().unwrap()).unwrap())
         }
         else { None }
 
@@ -78,7 +127,7 @@ impl Client {
 
     pub fn last_confirmed_block(&self) -> Option<Block> {
         if self.last_confirmed_block_id.is_some() {
-            Some(self.blocks.get(&self.last_confirmed_block_id.as_ref().unwrap()).unwrap().clone())
+            Some(self.blocks.get(self.last_confirmed_block_id.as_ref().unwrap()).unwrap())
         }
         else { None }
     }
@@ -92,11 +141,11 @@ impl Client {
     }
 
     pub fn available_gold(&self) -> u128 {
-        let mut pending_spent = 0;
-        for (_, tx) in &self.pending_outgoing_transactions{
-            pending_spent += tx.total_output();
+        let mut pending_spent:u128 = 0;
+        for tx in self.pending_outgoing_transactions.values() {
+            pending_spent = pending_spent.saturating_add(tx.total_output().unwrap_or(u128::MAX));
         }
-        self.confirmed_balance() - pending_spent
+        self.confirmed_balance().saturating_sub(pending_spent)
     }
 
     fn pub_key_bytes(&self) -> Vec<u8> {
@@ -104,17 +153,15 @@ impl Client {
     }
 
     pub fn address(&self) -> Address {
-        calc_address(self.keypair.public_key().as_ref())
+        calc_address(self.keypair.public_key().as_ref()).on_network(self.blockchain.address_hrp())
     }
 
-    /** Currently only creates and returns a tx if client has enough gold.
-           Will eventually broadcast this transaction to the network
+    /** Creates and returns a tx if client has enough gold.
+        Broadcasting is left to the caller, see `net::Node::post_transaction`.
      */
     pub fn post_transaction(&mut self, outputs:Vec<(Address, u128)>, custom_fee:Option<u32>) -> Option<Transaction> {
-        let mut fee = custom_fee.unwrap_or(DEFAULT_FEE);
-        if fee < DEFAULT_FEE {
-            fee = DEFAULT_FEE;
-        }
+        let default_fee = self.blockchain.default_tx_fee();
+        let fee = custom_fee.unwrap_or(default_fee).max(default_fee);
         let mut tx = Blockchain::make_transaction(
             self.address(),
             self.nonce,
@@ -123,79 +170,334 @@ impl Client {
             fee,
             "".to_string()
         );
-        if self.available_gold() > tx.total_output(){
-            tx.sign(&self.keypair);
+        tx.set_network(self.blockchain.address_hrp());
+        let total_output = match tx.total_output() {
+            Ok(total_output) => total_output,
+            Err(err) => {
+                self.log(&format!("Not posting tx: {}", err));
+                return None;
+            }
+        };
+        if self.available_gold() > total_output {
+            //gold is only available once there is a chain, so there is a chain id
+            tx.sign(&self.keypair, &self.chain_id()?);
             self.pending_outgoing_transactions.insert(tx.id(), tx.clone());
             self.nonce+=1;
             Some(tx)
         }
         else {
-            self.log(&format!("Insufficient funds. {} gold available, tx total output: {}", self.available_gold(), tx.total_output()));
+            self.log(&format!("Insufficient funds. {} gold available, tx total output: {}", self.available_gold(), total_output));
             None
         }
     }
 
-    pub fn receive_block(&mut self, mut block:Block) -> Option<Block>{
-        //will need to deserialize when blocks are communicated through network
-        if self.blocks.contains_key(&block.id()) { return None }
-        if !block.has_valid_proof() && !block.is_genesis() {
-            self.log(&format!("Block {} does not have a valid proof.", encode(&*block.id())));
-            return None;
+    /** Starts a transaction spending from the multisig account of `policy`, signed with this client's key
+        if it is one of the policy's. Pass it to the other co-signers' `cosign_transaction` until
+        `signatures_missing` is 0, then send it on like any other transaction.
+     */
+    pub fn post_multisig_transaction(&mut self, policy:&MultisigPolicy, outputs:Vec<(Address, u128)>, custom_fee:Option<u32>) -> Option<Transaction> {
+        let mut request = self.prepare_multisig_transaction(policy, outputs, custom_fee)?;
+        if policy.index_of(&self.pub_key_bytes()).is_some() {
+            request.sign(&self.keypair).ok()?;
         }
-        let prev_block:Option<&Block> = self.blocks.get(&block.prev_block_hash);
+        Some(request.transaction)
+    }
 
-        if prev_block.is_none() && !block.is_genesis() {
-            let mut stuck_blocks: Option<Vec<Block>> = self.pending_blocks.remove(&block.prev_block_hash); //get(&block.prev_block_hash).as_mut();
+    /** An unsigned transaction from the account of `public_key`, whose private key lives somewhere else.
+        Export it with `to_json`, sign it offline with `SigningRequest::sign`, then bring it back
+        through `import_signed_transaction`.
+     */
+    pub fn prepare_transaction(&mut self, public_key:&[u8], outputs:Vec<(Address, u128)>, custom_fee:Option<u32>) -> Option<SigningRequest> {
+        let tx = Transaction::new(calc_address(public_key), 0, public_key.to_vec(), outputs, self.fee_or_default(custom_fee), "".to_string());
+        self.signing_request(tx)
+    }
+
+    /** Like `prepare_transaction`, spending from the multisig account of `policy`. */
+    pub fn prepare_multisig_transaction(&mut self, policy:&MultisigPolicy, outputs:Vec<(Address, u128)>, custom_fee:Option<u32>) -> Option<SigningRequest> {
+        let tx = Transaction::new_multisig(policy.clone(), 0, outputs, self.fee_or_default(custom_fee), "".to_string());
+        self.signing_request(tx)
+    }
+
+    fn fee_or_default(&self, custom_fee:Option<u32>) -> u32 {
+        let default_fee = self.blockchain.default_tx_fee();
+        custom_fee.unwrap_or(default_fee).max(default_fee)
+    }
 
-            if stuck_blocks.is_none() {
-                //request missing block
-                stuck_blocks = Some(vec![]);
+    // fills in the sender's first nonce not taken by a request still outstanding, and checks the sender
+    // can pay for it on top of those
+    fn signing_request(&mut self, mut tx:Transaction) -> Option<SigningRequest> {
+        let last_block = self.last_block()?;
+        tx.set_network(self.blockchain.address_hrp());
+        let chain_nonce = last_block.nonce_of(&tx.from);
+        //requests below the chain's nonce were mined, or can't be anymore
+        self.prepared_requests.retain(|(from, nonce), _| from != &tx.from || *nonce >= chain_nonce);
+        let mut outstanding:u128 = 0;
+        tx.nonce = chain_nonce;
+        for ((_, nonce), prepared) in self.prepared_requests.range((tx.from.clone(), chain_nonce)..=(tx.from.clone(), u128::MAX)) {
+            outstanding = outstanding.saturating_add(prepared.total_output().unwrap_or(u128::MAX));
+            if *nonce == tx.nonce {
+                tx.nonce = match tx.nonce.checked_add(1) {
+                    Some(next_nonce) => next_nonce,
+                    None => {
+                        self.log(&format!("Not preparing tx: {}", ValidationError::NonceOverflow(tx.from.clone())));
+                        return None;
+                    }
+                };
             }
-            let mut new_stuck_blocks = stuck_blocks.unwrap();
-            new_stuck_blocks.push(block.clone());
-            self.pending_blocks.insert(block.prev_block_hash, new_stuck_blocks.clone());
+        }
+        let total_output = match tx.total_output() {
+            Ok(total_output) => total_output,
+            Err(err) => {
+                self.log(&format!("Not preparing tx: {}", err));
+                return None;
+            }
+        };
+        let available = last_block.balance_of(&tx.from).saturating_sub(outstanding);
+        if available < total_output {
+            self.log(&format!("Insufficient funds. {} gold available in {}, tx total output: {}", available, tx.from, total_output));
             return None;
         }
+        self.prepared_requests.insert((tx.from.clone(), tx.nonce), tx.clone());
+        Some(SigningRequest::new(tx, last_block.chain_id()))
+    }
 
-        if !block.is_genesis() {
-            let success = block.rerun(prev_block.unwrap());
-            if !success { return None}
+    /** Forgets a prepared request that will never be signed, freeing its nonce and its gold for the next one.
+        Only cancel requests that can't come back: if the old one is signed after all, it and the next
+        request spend the same nonce and only one of them can be mined. Returns whether it was outstanding.
+     */
+    pub fn cancel_signing_request(&mut self, request:&SigningRequest) -> bool {
+        let tx = &request.transaction;
+        let key = (tx.from.clone(), tx.nonce);
+        match self.prepared_requests.get(&key) {
+            Some(prepared) if prepared.id() == tx.id() => {
+                self.prepared_requests.remove(&key);
+                true
+            }
+            _ => false
+        }
+    }
+
+    /** Checks a request that came back signed against the current chain and returns the transaction,
+        ready to hand to a miner or `Node::broadcast_transaction`.
+     */
+    pub fn import_signed_transaction(&self, request:SigningRequest) -> Result<Transaction, ValidationError> {
+        let last_block = self.last_block().ok_or(ValidationError::NoChain)?;
+        let tx = request.transaction;
+        self.check_network(&tx)?;
+        tx.validate(&last_block)?;
+        let next_nonce = last_block.nonce_of(&tx.from);
+        if tx.nonce < next_nonce {
+            return Err(ValidationError::ReplayedNonce { expected: next_nonce, got: tx.nonce });
+        }
+        Ok(tx)
+    }
+
+    // addresses written down for another network mean the tx was meant for another chain
+    fn check_network(&self, tx:&Transaction) -> Result<(), ValidationError> {
+        let expected = self.blockchain.address_hrp();
+        match iter::once(&tx.from).chain(tx.outputs.iter().map(|(address, _)| address)).find(|address| address.network() != expected) {
+            Some(address) => Err(ValidationError::WrongNetwork { address: address.clone(), expected: expected.to_string() }),
+            None => Ok(())
+        }
+    }
+
+    /** Adds this client's signature to a multisig transaction someone else started. */
+    pub fn cosign_transaction(&self, tx:&mut Transaction) -> Result<(), ValidationError> {
+        let chain_id = self.chain_id().ok_or(ValidationError::NoChain)?;
+        tx.cosign(&self.keypair, &chain_id)
+    }
+
+    /** Validates and stores `block`, moving to whichever stored chain has the most work.
+        If that changes the tip, the switch is recorded for `take_reorg`.
+     */
+    pub fn receive_block(&mut self, block:Block) -> Result<Block, ValidationError> {
+        let old_tip = self.last_block_id.clone();
+        let result = self.accept_block(block);
+        if self.last_block_id != old_tip {
+            if let (Some(old_tip), Some(new_tip)) = (old_tip, self.last_block_id.clone()) {
+                self.last_reorg = Some(self.reorg_between(&old_tip, &new_tip));
+            }
+        }
+        result
+    }
+
+    fn accept_block(&mut self, mut block:Block) -> Result<Block, ValidationError> {
+        block.set_network(self.blockchain.address_hrp());
+        if self.blocks.contains(&block.id()) { return Err(ValidationError::DuplicateBlock(block.id())) }
+        let prev_block:Option<Block> = self.blocks.get(&block.header.prev_block_hash);
+        //the rules below are keyed off the height, so it has to be the real one
+        if let Some(prev_block) = &prev_block {
+            block.check_height(prev_block)?;
+        }
+        if !self.blockchain.follows_rules(&block) || (block.is_genesis() && !self.blocks.is_empty()) {
+            return Err(ValidationError::RuleMismatch(block.id()));
+        }
+        if !block.has_valid_proof() && !block.is_genesis() {
+            return Err(ValidationError::BadProof(block.id()));
+        }
+
+        if prev_block.is_none() && !block.is_genesis() {
+            //request missing block
+            let parent = block.header.prev_block_hash.clone();
+            self.hold_orphan(block);
+            return Err(ValidationError::UnknownParent(parent));
+        }
+
+        let mut parent_work = 0;
+        if let Some(prev_block) = prev_block {
+            if block.header.pow_target != self.blockchain.next_target(&prev_block, &*self.blocks) {
+                return Err(ValidationError::BadTarget(block.id()));
+            }
+            self.blockchain.check_timestamp(&block, &prev_block, &*self.blocks)?;
+            block.rerun(&prev_block)?;
+            parent_work = self.blocks.total_work(&prev_block.id()).unwrap_or(0);
         }
 
         //block is good
-        self.blocks.insert(block.id(), block.clone());
+        let total_work = parent_work.saturating_add(block_work(&block.header.pow_target));
+        self.blocks.insert(block.clone(), total_work)
+            .map_err(|err| ValidationError::StoreFailed { block_id: block.id(), reason: err.to_string() })?;
 
-        let current_length = match self.last_block() {
-            Some(last_block) => last_block.chain_length,
-            None => 0
-        };
-        if current_length < block.chain_length {
-            self.last_block_id = Some(block.id());
+        if self.blocks.tip() != self.last_block_id {
+            self.last_block_id = self.blocks.tip();
             self.set_last_confirmed();
         }
-        let unstuck_blocks:Vec<Block> = self.pending_blocks.remove(&block.id()).unwrap_or(vec![]);
+        let unstuck_blocks:Vec<Block> = self.pending_blocks.remove(&block.id()).unwrap_or_default();
         for unstuck_block in unstuck_blocks {
-            self.log(&format!("Processing unstuck block {}", encode(&*block.id())));
-            self.receive_block(unstuck_block);
+            self.log(&format!("Processing unstuck block {}", encode(&*unstuck_block.id())));
+            if let Err(err) = self.accept_block(unstuck_block) {
+                self.log(&format!("Rejected unstuck block: {}", err));
+            }
         }
-        return Some(block);
+        Ok(block)
+    }
 
+    /** Keeps a block whose parent hasn't arrived yet, until `accept_block` gets to the parent.
+        Its target can't be checked without the parent, but no real target is easier than the genesis one.
+        Once `MAX_PENDING_BLOCKS` are waiting, further orphans are dropped and have to be fetched again.
+     */
+    fn hold_orphan(&mut self, block:Block) {
+        if block.header.pow_target > self.blockchain.pow_target() {
+            return;
+        }
+        let pending:usize = self.pending_blocks.values().map(Vec::len).sum();
+        if pending >= MAX_PENDING_BLOCKS {
+            return;
+        }
+        let siblings = self.pending_blocks.entry(block.header.prev_block_hash.clone()).or_default();
+        if !siblings.iter().any(|sibling| sibling.id() == block.id()) {
+            siblings.push(block);
+        }
     }
 
-    //request missing block
+    /** Blocks leaving and joining the main chain when the tip moves from `old_tip` to `new_tip`. */
+    pub fn reorg_between(&self, old_tip:&Hash, new_tip:&Hash) -> Reorg {
+        let mut disconnected = vec![];
+        let mut connected = vec![];
+        let mut old_block = self.blocks.get(old_tip);
+        let mut new_block = self.blocks.get(new_tip);
+        while let (Some(old), Some(new)) = (&old_block, &new_block) {
+            if old.id() == new.id() { break }
+            if old.header.chain_length >= new.header.chain_length {
+                disconnected.push(old.id());
+                old_block = self.blocks.get(&old.header.prev_block_hash);
+            }
+            else {
+                connected.push(new.id());
+                new_block = self.blocks.get(&new.header.prev_block_hash);
+            }
+        }
+        connected.reverse();
+        Reorg { disconnected, connected }
+    }
 
-    //resend pending transactions
+    /** Checks the whole main chain against the issuance schedule: every block holds exactly
+        the genesis allocations plus what `blockchain` lets coinbases mint up to its height,
+        with fees neither created nor destroyed. Returns the current accounted supply.
+     */
+    pub fn audit_supply(&self) -> Result<u128, ValidationError> {
+        let mut chain = vec![];
+        let mut block = self.last_block();
+        while let Some(current) = block {
+            block = if current.is_genesis() { None } else { self.blocks.get(&current.header.prev_block_hash) };
+            chain.push(current);
+        }
+        chain.reverse();
+        let genesis = match chain.first() {
+            Some(genesis) => genesis,
+            None => return Ok(0)
+        };
+        let allocated = genesis.total_supply();
+        let mut expected = 0;
+        for block in &chain {
+            expected = allocated.saturating_add(self.blockchain.issued_through(block.header.chain_length));
+            let actual = block.accounted_supply();
+            if actual != expected {
+                return Err(ValidationError::SupplyMismatch { block_id: block.id(), expected, actual });
+            }
+        }
+        Ok(expected)
+    }
+
+    /** Accounts of `wallet` that show up on the main chain, for restoring a wallet from its mnemonic.
+        Empty until the client has synced.
+     */
+    pub fn rescan(&self, wallet:&HdWallet, gap_limit:u32) -> Vec<HdAccount> {
+        self.last_block().map_or_else(Vec::new, |tip| wallet.rescan(&tip, gap_limit))
+    }
+
+    /** All gold on the main chain, spendable or still maturing. */
+    pub fn circulating_supply(&self) -> u128 {
+        match self.last_block() {
+            Some(block) => block.accounted_supply(),
+            None => 0
+        }
+    }
+
+    /** The most recent tip change made by `receive_block`, if it has not been taken yet. */
+    pub fn take_reorg(&mut self) -> Option<Reorg> {
+        self.last_reorg.take()
+    }
+
+    /** Keeps track of incoming payments announced on the network until they are confirmed. */
+    pub fn receive_transaction(&mut self, mut tx:Transaction) -> Result<(), ValidationError> {
+        tx.set_network(self.blockchain.address_hrp());
+        if !tx.is_signed() { return Err(ValidationError::MissingSignature) }
+        tx.check_sender()?;
+        tx.check_outputs()?;
+        //with no chain yet there is nothing the signature could be valid for
+        let chain_id = self.chain_id().ok_or(ValidationError::NoChain)?;
+        tx.check_signatures(&chain_id)?;
+        let address = self.address();
+        if tx.outputs.iter().any(|(addr, _)| *addr == address) {
+            self.pending_received_transactions.insert(tx.id(), tx);
+        }
+        Ok(())
+    }
 
-    //provide missing block
+    /** Id of the genesis block, which transactions are signed for. */
+    pub fn chain_id(&self) -> Option<Hash> {
+        self.last_block().map(|block| block.chain_id())
+    }
+
+    pub fn genesis_block(&self) -> Option<Block> {
+        let mut block = self.last_block()?;
+        while !block.is_genesis() {
+            block = self.blocks.get(&block.header.prev_block_hash)?;
+        }
+        Some(block)
+    }
+
+    //resend pending transactions
 
     fn set_last_confirmed(&mut self) {
         if self.blocks.is_empty() {
             panic!("Trying to set last confirmed on empty blockchain");
         }
         let mut block = self.last_block().unwrap();
-        let confirmed_block_height = block.chain_length.checked_sub(CONFIRMED_DEPTH as u32).unwrap_or(0);
-        while block.chain_length > confirmed_block_height {
-            block = self.blocks.get(&block.prev_block_hash).unwrap().clone();
+        let confirmed_block_height = block.header.chain_length.checked_sub(self.blockchain.confirmed_depth() as u32).unwrap_or(0);
+        while block.header.chain_length > confirmed_block_height {
+            block = self.blocks.get(&block.header.prev_block_hash).unwrap();
         }
 
         self.pending_outgoing_transactions.retain( |id,_| !block.contains(id));
@@ -209,8 +511,8 @@ impl Client {
         self.log("Showing Balances:");
         let last_confirmed_block = self.last_confirmed_block();
         if last_confirmed_block.is_some(){
-            for (addr, balance) in &last_confirmed_block.unwrap().balances {
-                println!("{}: {}", addr, balance);
+            for (addr, account) in last_confirmed_block.unwrap().state.accounts() {
+                println!("{}: {}", addr, account.balance);
             }
         }
 
@@ -223,11 +525,11 @@ impl Client {
     pub fn show_blockchain(&self) {
         match self.last_block() {
             Some(block) => {
-                let mut block = Some(&block);
+                let mut block = Some(block);
                 self.log("BLOCKCHAIN:");
-                while block.is_some() {
-                    self.log(&format!("{:?}", encode(&*block.unwrap().id())));
-                    block = self.blocks.get(&block.unwrap().prev_block_hash);
+                while let Some(current) = block {
+                    self.log(&format!("{:?}", encode(&*current.id())));
+                    block = self.blocks.get(&current.header.prev_block_hash);
                 }
             },
             None => { self.log("Empty Blockchain") }
@@ -235,4 +537,130 @@ impl Client {
 
     }
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use crate::{calc_address, Block, Blockchain, Client, Miner, SigningRequest, Transaction, ValidationError};
+
+    fn setup() -> (Blockchain, Ed25519KeyPair, Client) {
+        let chain = Blockchain::new().with_pow_leading_zeros(0).with_retarget_interval(0).with_default_tx_fee(5);
+        let cold = Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap();
+        let genesis = chain.make_genesis(BTreeMap::from([(calc_address(cold.public_key().as_ref()), 100)]));
+        let online = Client::new("online".to_string(), chain.clone(), Some(genesis), None);
+        (chain, cold, online)
+    }
+
+    #[test]
+    fn prepares_signs_offline_and_imports() {
+        let (_, cold, mut online) = setup();
+        let request = online.prepare_transaction(cold.public_key().as_ref(), vec![(online.address(), 40)], None).unwrap();
+        assert_eq!(request.transaction.nonce, 0);
+        //only JSON crosses over to the offline machine and back
+        let mut offline = SigningRequest::from_json(&request.to_json()).unwrap();
+        offline.sign(&cold).unwrap();
+        assert!(offline.is_complete());
+        let tx = online.import_signed_transaction(SigningRequest::from_json(&offline.to_json()).unwrap()).unwrap();
+        assert_eq!(tx.nonce, 0);
+        assert!(online.import_signed_transaction(request).is_err());
+        //the first request's 45 is still outstanding, so 100 only covers one more
+        assert_eq!(online.prepare_transaction(cold.public_key().as_ref(), vec![(online.address(), 40)], None).unwrap().transaction.nonce, 1);
+        assert!(online.prepare_transaction(cold.public_key().as_ref(), vec![(online.address(), 40)], None).is_none());
+    }
+
+    #[test]
+    fn abandoned_request_can_be_cancelled() {
+        let (chain, cold, mut online) = setup();
+        let abandoned = online.prepare_transaction(cold.public_key().as_ref(), vec![(online.address(), 40)], None).unwrap();
+        let mut second = online.prepare_transaction(cold.public_key().as_ref(), vec![(online.address(), 10)], None).unwrap();
+        assert_eq!(second.transaction.nonce, 1);
+        assert!(online.cancel_signing_request(&abandoned));
+        assert!(!online.cancel_signing_request(&abandoned));
+        //the gap is filled rather than leaving the second request stuck behind it
+        let mut replacement = online.prepare_transaction(cold.public_key().as_ref(), vec![(online.address(), 20)], None).unwrap();
+        assert_eq!(replacement.transaction.nonce, 0);
+        replacement.sign(&cold).unwrap();
+        second.sign(&cold).unwrap();
+        let mut miner = Miner::new("miner".to_string(), chain, online.genesis_block(), None, None);
+        miner.initialize();
+        for request in [replacement, second] {
+            miner.receive_transaction(online.import_signed_transaction(request).unwrap()).unwrap();
+        }
+        miner.start_new_search(None);
+        let block = miner.mine_block();
+        online.receive_block(block).unwrap();
+        //both were mined, so nothing is outstanding and only the chain's 60 bounds the next one
+        let next = online.prepare_transaction(cold.public_key().as_ref(), vec![(online.address(), 35)], None).unwrap();
+        assert_eq!(next.transaction.nonce, 2);
+    }
+
+    // a child of `client`'s tip stamped `timestamp`
+    fn mined_at(client:&Client, timestamp:u128) -> Block {
+        let tip = client.last_block().unwrap();
+        let mut block = client.blockchain.make_block(client.address(), &tip, &*client.blocks).unwrap();
+        block.header.timestamp = timestamp;
+        while !block.header.has_valid_proof() {
+            block.header.proof += 1;
+        }
+        block
+    }
+
+    #[test]
+    fn follows_the_chain_with_most_work() {
+        let chain = Blockchain::new().with_pow_leading_zeros(1).with_retarget_interval(1).with_target_block_time(1000);
+        let genesis = chain.make_genesis(BTreeMap::new());
+        let start = genesis.header.timestamp;
+        let mut node = Client::new("node".to_string(), chain.clone(), Some(genesis.clone()), None);
+        let mut slow = vec![];
+        for i in 1..=6 {
+            let block = mined_at(&node, start + 4000 * i);
+            node.receive_block(block.clone()).unwrap();
+            slow.push(block.id());
+        }
+        node.take_reorg();
+        //three blocks, each mined four times faster than the last, so they get harder and harder
+        let mut fast_miner = Client::new("fast".to_string(), chain, Some(genesis), None);
+        let mut fast = vec![];
+        for i in 1..=3 {
+            let block = mined_at(&fast_miner, start + 250 * i);
+            fast_miner.receive_block(block.clone()).unwrap();
+            fast.push(block);
+        }
+        node.receive_block(fast[0].clone()).unwrap();
+        node.receive_block(fast[1].clone()).unwrap();
+        //1 + 4 times the work of a slow block is still less than 6
+        assert_eq!(node.last_block().unwrap().id(), slow[5]);
+        assert!(node.take_reorg().is_none());
+        node.receive_block(fast[2].clone()).unwrap();
+        assert_eq!(node.last_block().unwrap().id(), fast[2].id());
+        let reorg = node.take_reorg().unwrap();
+        slow.reverse();
+        assert_eq!(reorg.disconnected, slow);
+        assert_eq!(reorg.connected, fast.iter().map(Block::id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn pays_fees_to_the_miner_and_mints_nothing_else() {
+        let chain = Blockchain::new().with_pow_leading_zeros(0).with_retarget_interval(0).with_coinbase_reward(10);
+        let payer = Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap();
+        let from = calc_address(payer.public_key().as_ref());
+        let genesis = chain.make_genesis(BTreeMap::from([(from.clone(), 100)]));
+        let mut client = Client::new("client".to_string(), chain.clone(), Some(genesis.clone()), None);
+        let mut tx = Transaction::new(from.clone(), 0, payer.public_key().as_ref().to_vec(), vec![(client.address(), 20)], 3, String::new());
+        tx.sign(&payer, &genesis.chain_id());
+        let mut block = chain.make_block(client.address(), &genesis, &*client.blocks).unwrap();
+        block.add_transaction(tx).unwrap();
+        assert_eq!(block.coinbase.outputs, vec![(client.address(), 13)]);
+
+        let mut greedy = block.clone();
+        greedy.coinbase = Transaction::coinbase(1, client.address(), 14);
+        greedy.header.transactions_root = greedy.transactions_root();
+        assert_eq!(client.receive_block(greedy.clone()).err(), Some(ValidationError::BadCoinbase(greedy.id())));
+
+        client.receive_block(block).unwrap();
+        //the fee moved from the payer to the coinbase, only the reward is new
+        assert_eq!(client.audit_supply(), Ok(110));
+        assert_eq!(client.last_block().unwrap().balance_of(&from), 77);
+    }
+}
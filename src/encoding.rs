@@ -0,0 +1,302 @@
+use std::error::Error;
+use std::fmt;
+use crate::{u128_to_bytes, u32_to_bytes, u64_to_bytes, Address, Hash, HASH_LEN};
+
+/** First byte of every top level encoding. Bumped whenever the layout changes.
+
+    Layout, all integers little-endian:
+    - `u8`, `u32`, `u64`, `u128`: fixed width
+    - hash: 32 raw bytes
+    - bytes and strings: `u32` length, then the bytes (strings as UTF-8)
+    - address: bytes holding the kind byte and then the payload, as `Address::to_bytes`
+    - lists: `u32` count, then each item
+    - optional signature: `u8` 0 for none, or 1 followed by the 64 signature bytes
+
+    A multisig policy is `threshold:u8, keys:list of 32 byte public keys`, the keys sorted. A multisig
+    address's payload is the SHA-256 of that.
+
+    A transaction is `from:address, nonce:u128, pubkey:bytes, outputs:list of (address, amount:u128),
+    fee:u32, data:string, multisig`, where multisig is `u8` 0, or 1 followed by the policy. Its id is the
+    SHA-256 of the version byte plus those fields. What gets signed is `SIGNING_DOMAIN`, the chain id
+    and then the tx id, with no length prefixes. Its wire format appends the optional signature and
+    then the cosignatures, a list of (key index:u8, 64 signature bytes) in index order.
+
+    A block header is `prev_block_hash, transactions_root, state_root, reward_addr:address,
+    coinbase_reward:u128, coinbase_maturity:u32, timestamp:u128, pow_target, proof:u128, chain_length:u32`.
+    Its id is the SHA-256 of the version byte plus those fields.
+
+    A block is the version byte, the header fields, the coinbase and then every other transaction
+    (both in wire format without their version byte), then the genesis allocations as a list of
+    (address, balance:u128). The header's `transactions_root` is the Merkle root of the SHA-256 of each
+    transaction's full wire format, coinbase first, so it covers the signatures that the tx ids leave out.
+
+    Test vectors, using the ed25519 keys with seeds of 32 `0x01`, `0x02`, `0x03` and `0x04` bytes:
+    - `rg1qz9g3c7awsylr90a2tdj6096t4ev5ecfhuwegysm7d6gsqd5pah4cu89cky`
+    - `rg1qzqnjacw4p73wh6k5d2xds6v0mxvhrv2jx6wudazthmq7ku0exeega43udq` (bob)
+    - `rg1qrk5j2xx9rgu93h2aypn3yzej4sjjkf88fwx87fkxmq5v99vsumaz89mhx2` (carol)
+    - `rg1qr9f8tqhq5v8quwk0wpu0lcwl6qs368vg5c9whthy6rexv7mm2l8cudwqxa` (miner)
+
+    Transaction from the first address with nonce 7, outputs `[(bob, 1000), (carol, 5)]`, fee 2 and data "hi".
+    Id preimage:
+    `03 21000000 00 8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c 07000000000000000000000000000000 20000000 8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c 02000000 21000000 00 8139770ea87d175f56a35466c34c7ecccb8d8a91b4ee37a25df60f5b8fc9b394 e8030000000000000000000000000000 21000000 00 ed4928c628d1c2c6eae90338905995612959273a5c63f93636c14614ac8737d1 05000000000000000000000000000000 02000000 02000000 6869 00`
+    id `f5d101c1ac7a996318af864a52b39789f7b7777c301ccac84e0a4486ee00aece`
+    Signing preimage for a chain id of 32 `0x02` bytes:
+    `7275737465642d676f6c64207472616e73616374696f6e 0202020202020202020202020202020202020202020202020202020202020202 f5d101c1ac7a996318af864a52b39789f7b7777c301ccac84e0a4486ee00aece`
+    Signed for that chain, its wire format is the id preimage followed by
+    `01 808eaf2c20b52845e2a94a02a26ce625c48179a293d224aeec3adbca46033ab743f9f9885db710b33fed2ae580f807c9287a7023320b69551f45ba0ae2eb8509 00000000`
+
+    Header with zero `prev_block_hash`, `transactions_root` and `state_root`, reward_addr miner,
+    coinbase_reward 25, coinbase_maturity 5, timestamp 1700000000000, `pow_target` all `0xff`, proof 42 and chain_length 3:
+    `03 0000000000000000000000000000000000000000000000000000000000000000 0000000000000000000000000000000000000000000000000000000000000000 0000000000000000000000000000000000000000000000000000000000000000 21000000 00 ca93ac1705187071d67b83c7ff0efe8108e8ec4530575d7726879333dbdabe7c 19000000000000000000000000000000 05000000 0068e5cf8b0100000000000000000000 ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff 2a000000000000000000000000000000 03000000`
+    id `6e64ebe8d2253081b8353f0da3f0db9739d9c79bc6d6391c25df70894c03329d`
+ */
+pub const ENCODING_VERSION:u8 = 3;
+
+/** Why bytes could not be decoded. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnexpectedEnd,
+    UnsupportedVersion(u8),
+    InvalidUtf8,
+    InvalidFlag(u8),
+    TrailingBytes(usize),
+    Invalid(String)
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "input ended early"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported encoding version {}", version),
+            DecodeError::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            DecodeError::InvalidFlag(flag) => write!(f, "invalid flag byte {}", flag),
+            DecodeError::TrailingBytes(count) => write!(f, "{} bytes left over", count),
+            DecodeError::Invalid(reason) => write!(f, "{}", reason)
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+#[derive(Default)]
+pub struct Encoder {
+    bytes:Vec<u8>
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder::default()
+    }
+
+    /** An encoder that already holds the version byte. */
+    pub fn versioned() -> Self {
+        let mut encoder = Encoder::new();
+        encoder.u8(ENCODING_VERSION);
+        encoder
+    }
+
+    pub fn u8(&mut self, value:u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u32(&mut self, value:u32) {
+        self.bytes.extend_from_slice(&u32_to_bytes(&value));
+    }
+
+    pub fn u64(&mut self, value:u64) {
+        self.bytes.extend_from_slice(&u64_to_bytes(&value));
+    }
+
+    pub fn u128(&mut self, value:u128) {
+        self.bytes.extend_from_slice(&u128_to_bytes(&value));
+    }
+
+    pub fn hash(&mut self, hash:&Hash) {
+        self.fixed(hash);
+    }
+
+    /** Fixed length bytes, no length prefix. */
+    pub fn fixed(&mut self, bytes:&[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn bytes(&mut self, bytes:&[u8]) {
+        self.count(bytes.len());
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn str(&mut self, value:&str) {
+        self.bytes(value.as_bytes());
+    }
+
+    pub fn address(&mut self, address:&Address) {
+        self.bytes(&address.to_bytes());
+    }
+
+    /** A list or byte string length. Nothing this crate encodes comes near `u32::MAX`. */
+    pub fn count(&mut self, count:usize) {
+        self.u32(u32::try_from(count).expect("too long to encode"));
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct Decoder<'a> {
+    bytes:&'a [u8],
+    pos:usize
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes:&'a [u8]) -> Self {
+        Decoder { bytes, pos: 0 }
+    }
+
+    fn take(&mut self, count:usize) -> Result<&'a [u8], DecodeError> {
+        let end = self.pos.checked_add(count).ok_or(DecodeError::UnexpectedEnd)?;
+        let taken = self.bytes.get(self.pos..end).ok_or(DecodeError::UnexpectedEnd)?;
+        self.pos = end;
+        Ok(taken)
+    }
+
+    /** Reads the version byte and rejects any version this build doesn't know. */
+    pub fn version(&mut self) -> Result<(), DecodeError> {
+        match self.u8()? {
+            ENCODING_VERSION => Ok(()),
+            version => Err(DecodeError::UnsupportedVersion(version))
+        }
+    }
+
+    pub fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn u128(&mut self) -> Result<u128, DecodeError> {
+        Ok(u128::from_le_bytes(self.take(16)?.try_into().unwrap()))
+    }
+
+    pub fn hash(&mut self) -> Result<Hash, DecodeError> {
+        Ok(Hash(self.fixed(HASH_LEN)?))
+    }
+
+    /** Exactly `len` bytes, no length prefix. */
+    pub fn fixed(&mut self, len:usize) -> Result<Vec<u8>, DecodeError> {
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let len = self.count()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn string(&mut self) -> Result<String, DecodeError> {
+        String::from_utf8(self.bytes()?).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    pub fn address(&mut self) -> Result<Address, DecodeError> {
+        Address::from_bytes(&self.bytes()?).map_err(|err| DecodeError::Invalid(err.to_string()))
+    }
+
+    pub fn count(&mut self) -> Result<usize, DecodeError> {
+        Ok(self.u32()? as usize)
+    }
+
+    /** Errors unless every byte was read. */
+    pub fn finish(self) -> Result<(), DecodeError> {
+        match self.bytes.len() - self.pos {
+            0 => Ok(()),
+            left => Err(DecodeError::TrailingBytes(left))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use crate::{calc_address, Address, BlockHeader, Blockchain, Hash, Transaction};
+
+    fn keypair(seed:u8) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap()
+    }
+
+    fn address(seed:u8) -> Address {
+        calc_address(keypair(seed).public_key().as_ref())
+    }
+
+    // the vectors above are written in space separated groups
+    fn unspaced(vector:&str) -> String {
+        vector.replace(' ', "")
+    }
+
+    fn vector_tx() -> Transaction {
+        Transaction::new(address(1), 7, keypair(1).public_key().as_ref().to_vec(),
+            vec![(address(2), 1000), (address(3), 5)], 2, "hi".to_string())
+    }
+
+    const TX_PREIMAGE:&str = "03 21000000 00 8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c 07000000000000000000000000000000 20000000 8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c 02000000 21000000 00 8139770ea87d175f56a35466c34c7ecccb8d8a91b4ee37a25df60f5b8fc9b394 e8030000000000000000000000000000 21000000 00 ed4928c628d1c2c6eae90338905995612959273a5c63f93636c14614ac8737d1 05000000000000000000000000000000 02000000 02000000 6869 00";
+
+    #[test]
+    fn address_vectors() {
+        assert_eq!(address(1).to_string(), "rg1qz9g3c7awsylr90a2tdj6096t4ev5ecfhuwegysm7d6gsqd5pah4cu89cky");
+        assert_eq!(address(2).to_string(), "rg1qzqnjacw4p73wh6k5d2xds6v0mxvhrv2jx6wudazthmq7ku0exeega43udq");
+        assert_eq!(address(3).to_string(), "rg1qrk5j2xx9rgu93h2aypn3yzej4sjjkf88fwx87fkxmq5v99vsumaz89mhx2");
+        assert_eq!(address(4).to_string(), "rg1qr9f8tqhq5v8quwk0wpu0lcwl6qs368vg5c9whthy6rexv7mm2l8cudwqxa");
+    }
+
+    #[test]
+    fn transaction_vectors() {
+        let mut tx = vector_tx();
+        assert_eq!(hex::encode(tx.id_preimage()), unspaced(TX_PREIMAGE));
+        assert_eq!(tx.id().as_hex(), "f5d101c1ac7a996318af864a52b39789f7b7777c301ccac84e0a4486ee00aece");
+
+        let chain_id = Hash(vec![2; 32]);
+        assert_eq!(hex::encode(tx.signing_preimage(&chain_id)), unspaced("7275737465642d676f6c64207472616e73616374696f6e 0202020202020202020202020202020202020202020202020202020202020202 f5d101c1ac7a996318af864a52b39789f7b7777c301ccac84e0a4486ee00aece"));
+
+        tx.sign(&keypair(1), &chain_id);
+        let wire = tx.to_bytes();
+        assert_eq!(hex::encode(&wire), unspaced(TX_PREIMAGE) + &unspaced("01 808eaf2c20b52845e2a94a02a26ce625c48179a293d224aeec3adbca46033ab743f9f9885db710b33fed2ae580f807c9287a7023320b69551f45ba0ae2eb8509 00000000"));
+        let decoded = Transaction::from_bytes(&wire).unwrap();
+        assert_eq!(decoded.to_bytes(), wire);
+        assert!(decoded.valid_signature(&chain_id));
+    }
+
+    #[test]
+    fn header_vector() {
+        let header = BlockHeader {
+            prev_block_hash: Hash(vec![0; 32]),
+            transactions_root: Hash(vec![0; 32]),
+            state_root: Hash(vec![0; 32]),
+            reward_addr: address(4),
+            coinbase_reward: 25,
+            coinbase_maturity: 5,
+            timestamp: 1700000000000,
+            pow_target: Blockchain::new().with_pow_leading_zeros(0).pow_target(),
+            proof: 42,
+            chain_length: 3
+        };
+        assert_eq!(hex::encode(header.to_bytes()), unspaced("03 0000000000000000000000000000000000000000000000000000000000000000 0000000000000000000000000000000000000000000000000000000000000000 0000000000000000000000000000000000000000000000000000000000000000 21000000 00 ca93ac1705187071d67b83c7ff0efe8108e8ec4530575d7726879333dbdabe7c 19000000000000000000000000000000 05000000 0068e5cf8b0100000000000000000000 ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff 2a000000000000000000000000000000 03000000"));
+        assert_eq!(header.id().as_hex(), "6e64ebe8d2253081b8353f0da3f0db9739d9c79bc6d6391c25df70894c03329d");
+        assert_eq!(BlockHeader::from_bytes(&header.to_bytes()).unwrap(), header);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let mut tx = vector_tx();
+        tx.sign(&keypair(1), &Hash(vec![2; 32]));
+        let mut wire = tx.to_bytes();
+        assert_eq!(Transaction::from_bytes(&wire[..wire.len() - 1]).err(), Some(super::DecodeError::UnexpectedEnd));
+        wire.push(0);
+        assert_eq!(Transaction::from_bytes(&wire).err(), Some(super::DecodeError::TrailingBytes(1)));
+        wire[0] = 9;
+        assert_eq!(Transaction::from_bytes(&wire).err(), Some(super::DecodeError::UnsupportedVersion(9)));
+    }
+}
@@ -0,0 +1,89 @@
+use std::error::Error;
+use std::fmt;
+use crate::{Address, Hash};
+
+/** Why a block or transaction was rejected. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    DuplicateTx(Hash),
+    MissingSignature,
+    BadSignature,
+    AddressMismatch { from:Address, derived:Address },
+    WrongNetwork { address:Address, expected:String },
+    InvalidMultisig(String),
+    NotEnoughSignatures { required:u8, valid:usize },
+    NotACosigner(Address),
+    InsufficientFunds { address:Address, available:u128, required:u128 },
+    ReplayedNonce { expected:u128, got:u128 },
+    FutureNonce { expected:u128, got:u128 },
+    FeeTooLow { tx_id:Hash, required:u128 },
+    OutputOverflow(Hash),
+    InvalidOutput { tx_id:Hash, address:Address },
+    BalanceOverflow(Address),
+    NonceOverflow(Address),
+    DuplicateBlock(Hash),
+    BadProof(Hash),
+    BadTarget(Hash),
+    TimestampTooEarly { block_id:Hash, median_time_past:u128 },
+    TimestampTooLate { block_id:Hash, limit:u128 },
+    UnknownParent(Hash),
+    NoChain,
+    WrongHeight { block_id:Hash, expected:u32, got:u32 },
+    RuleMismatch(Hash),
+    RootMismatch(Hash),
+    BadCoinbase(Hash),
+    SupplyMismatch { block_id:Hash, expected:u128, actual:u128 },
+    StoreFailed { block_id:Hash, reason:String },
+    BadTransaction { tx_id:Hash, reason:Box<ValidationError> }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::DuplicateTx(id) => write!(f, "duplicate tx {}", id.as_hex()),
+            ValidationError::MissingSignature => write!(f, "no signature"),
+            ValidationError::BadSignature => write!(f, "invalid signature"),
+            ValidationError::NoChain => write!(f, "no chain yet to check against"),
+            ValidationError::AddressMismatch { from, derived } =>
+                write!(f, "tx claims to be from {} but its key belongs to {}", from, derived),
+            ValidationError::WrongNetwork { address, expected } =>
+                write!(f, "{} is for network {}, not {}", address, address.network(), expected),
+            ValidationError::InvalidMultisig(reason) => write!(f, "invalid multisig: {}", reason),
+            ValidationError::NotEnoughSignatures { required, valid } =>
+                write!(f, "{} valid signatures, {} required", valid, required),
+            ValidationError::NotACosigner(address) => write!(f, "{} is not one of the account's keys", address),
+            ValidationError::InsufficientFunds { address, available, required } =>
+                write!(f, "insufficient funds for {}: {} available, {} required", address, available, required),
+            ValidationError::ReplayedNonce { expected, got } =>
+                write!(f, "replayed tx: nonce {} already used, expected {}", got, expected),
+            ValidationError::FutureNonce { expected, got } =>
+                write!(f, "out of order tx: nonce {}, expected {}", got, expected),
+            ValidationError::FeeTooLow { tx_id, required } =>
+                write!(f, "tx {} fee too low, at least {} required", tx_id.as_hex(), required),
+            ValidationError::OutputOverflow(id) => write!(f, "tx {} outputs and fee add up to more than a u128", id.as_hex()),
+            ValidationError::InvalidOutput { tx_id, address } =>
+                write!(f, "tx {} pays {}, which can't receive gold", tx_id.as_hex(), address),
+            ValidationError::BalanceOverflow(address) => write!(f, "balance of {} would overflow", address),
+            ValidationError::NonceOverflow(address) => write!(f, "{} has used every nonce", address),
+            ValidationError::DuplicateBlock(id) => write!(f, "block {} already known", id.as_hex()),
+            ValidationError::BadProof(id) => write!(f, "block {} does not have a valid proof", id.as_hex()),
+            ValidationError::BadTarget(id) => write!(f, "block {} is not mined at the expected difficulty", id.as_hex()),
+            ValidationError::TimestampTooEarly { block_id, median_time_past } =>
+                write!(f, "block {} is timestamped before its ancestors' median time {}", block_id.as_hex(), median_time_past),
+            ValidationError::TimestampTooLate { block_id, limit } =>
+                write!(f, "block {} is timestamped after {}, too far in the future", block_id.as_hex(), limit),
+            ValidationError::UnknownParent(id) => write!(f, "parent block {} is unknown", id.as_hex()),
+            ValidationError::WrongHeight { block_id, expected, got } =>
+                write!(f, "block {} claims height {}, its parent makes it {}", block_id.as_hex(), got, expected),
+            ValidationError::RuleMismatch(id) => write!(f, "block {} does not follow the chain rules", id.as_hex()),
+            ValidationError::RootMismatch(id) => write!(f, "block {} header roots do not match its body", id.as_hex()),
+            ValidationError::BadCoinbase(id) => write!(f, "block {} coinbase does not pay the reward plus fees", id.as_hex()),
+            ValidationError::SupplyMismatch { block_id, expected, actual } =>
+                write!(f, "block {} accounts for {} gold, expected {}", block_id.as_hex(), actual, expected),
+            ValidationError::StoreFailed { block_id, reason } => write!(f, "block {} could not be stored: {}", block_id.as_hex(), reason),
+            ValidationError::BadTransaction { tx_id, reason } => write!(f, "tx {} rejected: {}", tx_id.as_hex(), reason)
+        }
+    }
+}
+
+impl Error for ValidationError {}
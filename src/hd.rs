@@ -0,0 +1,229 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use bip39::Mnemonic;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use crate::{calc_address, Address, Block};
+
+/** SLIP-0044 purpose. Every account path starts `m/44'/HD_COIN_TYPE'`. */
+pub const HD_PURPOSE:u32 = 44;
+/** Coin type in account paths. Not registered with SLIP-0044, so pick a different chain's at your peril. */
+pub const HD_COIN_TYPE:u32 = 7777;
+/** How many unused accounts in a row `rescan` looks at before it decides there are no more. */
+pub const DEFAULT_GAP_LIMIT:u32 = 20;
+const HARDENED:u32 = 0x8000_0000;
+const SEED_KEY:&[u8] = b"ed25519 seed";
+// OneAsymmetricKey v2 (RFC 5958) framing that ring expects around an ed25519 seed and public key
+const PKCS8_PREFIX:[u8; 16] = [0x30, 0x53, 0x02, 0x01, 0x01, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20];
+const PKCS8_MIDDLE:[u8; 5] = [0xa1, 0x23, 0x03, 0x21, 0x00];
+
+/** Why a mnemonic, seed or derivation path was rejected. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HdError {
+    InvalidMnemonic(String),
+    InvalidSeedLength(usize),
+    InvalidPath(String)
+}
+
+impl fmt::Display for HdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HdError::InvalidMnemonic(reason) => write!(f, "invalid mnemonic: {}", reason),
+            HdError::InvalidSeedLength(len) => write!(f, "seed is {} bytes, not 16 to 64", len),
+            HdError::InvalidPath(path) => write!(f, "invalid derivation path {}", path)
+        }
+    }
+}
+
+impl Error for HdError {}
+
+/** A path like `m/44'/7777'/0'`. ed25519 under SLIP-0010 only has hardened children,
+    so every index must be marked with `'` (or `h`).
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DerivationPath(Vec<u32>);
+
+impl DerivationPath {
+    /** Path of account `index`: `m/44'/HD_COIN_TYPE'/index'`. */
+    pub fn account(index:u32) -> Self {
+        DerivationPath(vec![HD_PURPOSE | HARDENED, HD_COIN_TYPE | HARDENED, index | HARDENED])
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.0
+    }
+}
+
+impl FromStr for DerivationPath {
+    type Err = HdError;
+
+    fn from_str(path:&str) -> Result<Self, Self::Err> {
+        let invalid = || HdError::InvalidPath(path.to_string());
+        let mut segments = path.split('/');
+        if segments.next() != Some("m") {
+            return Err(invalid());
+        }
+        let mut indices = vec![];
+        for segment in segments {
+            let index = segment.strip_suffix('\'').or_else(|| segment.strip_suffix('h')).ok_or_else(invalid)?;
+            let index:u32 = index.parse().map_err(|_| invalid())?;
+            if index >= HARDENED {
+                return Err(invalid());
+            }
+            indices.push(index | HARDENED);
+        }
+        Ok(DerivationPath(indices))
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m")?;
+        for index in &self.0 {
+            write!(f, "/{}'", index & !HARDENED)?;
+        }
+        Ok(())
+    }
+}
+
+// SLIP-0010: the left half of the HMAC-SHA512 is the key, the right half the chain code
+fn split_hmac(key:&[u8], data:&[u8]) -> ([u8; 32], [u8; 32]) {
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA512, key), data);
+    let (left, right) = tag.as_ref().split_at(32);
+    (left.try_into().unwrap(), right.try_into().unwrap())
+}
+
+/** An account the chain shows was used, found by `HdWallet::rescan`. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HdAccount {
+    pub index:u32,
+    pub address:Address,
+    pub balance:u128
+}
+
+/** Keys derived from a BIP39 mnemonic with SLIP-0010. Writing the mnemonic down is enough
+    to get every account back.
+ */
+pub struct HdWallet {
+    seed:Vec<u8>
+}
+
+impl HdWallet {
+    /** A fresh wallet and the mnemonic that restores it. `word_count` is 12, 15, 18, 21 or 24. */
+    pub fn generate(word_count:usize, passphrase:&str) -> Result<(Self, String), HdError> {
+        if !(12..=24).contains(&word_count) || !word_count.is_multiple_of(3) {
+            return Err(HdError::InvalidMnemonic(format!("{} words", word_count)));
+        }
+        let mut entropy = vec![0u8; word_count / 3 * 4];
+        SystemRandom::new().fill(&mut entropy).map_err(|_| HdError::InvalidMnemonic("no randomness available".to_string()))?;
+        let mnemonic = Mnemonic::from_entropy(&entropy).map_err(|err| HdError::InvalidMnemonic(err.to_string()))?;
+        Ok((HdWallet { seed: mnemonic.to_seed(passphrase).to_vec() }, mnemonic.to_string()))
+    }
+
+    /** The wallet behind `phrase`. The passphrase is the optional BIP39 one; a different passphrase is a different wallet. */
+    pub fn from_mnemonic(phrase:&str, passphrase:&str) -> Result<Self, HdError> {
+        let mnemonic = Mnemonic::parse(phrase).map_err(|err| HdError::InvalidMnemonic(err.to_string()))?;
+        Ok(HdWallet { seed: mnemonic.to_seed(passphrase).to_vec() })
+    }
+
+    /** A wallet straight from a binary seed (16 to 64 bytes), skipping the mnemonic. */
+    pub fn from_seed(seed:&[u8]) -> Result<Self, HdError> {
+        if !(16..=64).contains(&seed.len()) {
+            return Err(HdError::InvalidSeedLength(seed.len()));
+        }
+        Ok(HdWallet { seed: seed.to_vec() })
+    }
+
+    /** The 32 byte ed25519 seed at `path`. */
+    pub fn derive_seed(&self, path:&DerivationPath) -> [u8; 32] {
+        let (mut key, mut chain_code) = split_hmac(SEED_KEY, &self.seed);
+        for index in path.indices() {
+            let mut data = Vec::with_capacity(37);
+            data.push(0);
+            data.extend_from_slice(&key);
+            data.extend_from_slice(&index.to_be_bytes());
+            (key, chain_code) = split_hmac(&chain_code, &data);
+        }
+        key
+    }
+
+    pub fn derive(&self, path:&DerivationPath) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&self.derive_seed(path)).unwrap()
+    }
+
+    /** The key at `path` as a PKCS#8 document, for `Keystore::import_pkcs8`. */
+    pub fn derive_pkcs8(&self, path:&DerivationPath) -> Vec<u8> {
+        let seed = self.derive_seed(path);
+        let keypair = Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
+        [&PKCS8_PREFIX[..], &seed, &PKCS8_MIDDLE, keypair.public_key().as_ref()].concat()
+    }
+
+    pub fn account(&self, index:u32) -> Ed25519KeyPair {
+        self.derive(&DerivationPath::account(index))
+    }
+
+    pub fn address(&self, index:u32) -> Address {
+        calc_address(self.account(index).public_key().as_ref())
+    }
+
+    /** Every account with any trace in `tip`'s state: a balance, a spent nonce or an immature
+        coinbase. Stops after `gap_limit` unused accounts in a row, so accounts handed out
+        further apart than that are missed.
+     */
+    pub fn rescan(&self, tip:&Block, gap_limit:u32) -> Vec<HdAccount> {
+        let mut used = vec![];
+        let mut gap = 0;
+        let mut index = 0;
+        while gap < gap_limit && index < HARDENED {
+            let address = self.address(index);
+            let touched = tip.state.get(&address).is_some()
+                || tip.state.immature.values().any(|(owner, _)| *owner == address)
+                || tip.coinbase.outputs.iter().any(|(owner, _)| *owner == address);
+            if touched {
+                used.push(HdAccount { index, balance: tip.balance_of(&address), address });
+                gap = 0;
+            }
+            else {
+                gap += 1;
+            }
+            index += 1;
+        }
+        used
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::KeyPair;
+    use super::{DerivationPath, HdWallet};
+
+    // SLIP-0010 test vector 1 for ed25519
+    const SEED:&str = "000102030405060708090a0b0c0d0e0f";
+
+    fn check(path:&str, private_key:&str, public_key:&str) {
+        let wallet = HdWallet::from_seed(&hex::decode(SEED).unwrap()).unwrap();
+        let path:DerivationPath = path.parse().unwrap();
+        assert_eq!(hex::encode(wallet.derive_seed(&path)), private_key);
+        assert_eq!(hex::encode(wallet.derive(&path).public_key().as_ref()), public_key);
+    }
+
+    #[test]
+    fn slip10_vector_1() {
+        check("m", "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7", "a4b2856bfec510abab89753fac1ac0e1112364e7d250545963f135f2a33188ed");
+        check("m/0'", "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3", "8c8a13df77a28f3445213a0f432fde644acaa215fc72dcdf300d5efaa85d350c");
+        check("m/0'/1'", "b1d0bad404bf35da785a64ca1ac54b2617211d2777696fbffaf208f746ae84f2", "1932a5270f335bed617d5b935c80aedb1a35bd9fc1e31acafd5372c30f5c1187");
+        check("m/0'/1'/2'", "92a5b23c0b8a99e37d07df3fb9966917f5d06e02ddbd909c7e184371463e9fc9", "ae98736566d30ed0e9d2f4486a64bc95740d89c7db33f52121f8ea8f76ff0fc1");
+        check("m/0'/1'/2'/2'", "30d1dc7e5fc04c31219ab25a27ae00b50f6fd66622f6e9c913253d6511d1e662", "8abae2d66361c879b900d204ad2cc4984fa2aa344dd7ddc46007329ac76c429c");
+        check("m/0h/1'/2'/2'/1000000000'", "8f94d394a8e8fd6b1bc2f3f49f5c47e385281d5c17e65324b0f62483e37e8793", "3c24da049451555d51a7014a37337aa4e12d41e485abccfa46b47dfb2af54b7a");
+    }
+
+    #[test]
+    fn paths_must_be_hardened() {
+        assert!("m/0".parse::<DerivationPath>().is_err());
+        assert!("x/0'".parse::<DerivationPath>().is_err());
+        assert!("m/2147483648'".parse::<DerivationPath>().is_err());
+        assert_eq!("m/44'/7777'/3'".parse::<DerivationPath>().unwrap(), DerivationPath::account(3));
+    }
+}
@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use crate::blockchain::{COINBASE_REWARD, HALVING_INTERVAL};
+
+/** How much new gold the coinbase at each height mints, before any supply cap.
+    In a chain spec it is written as e.g. `{"halving": {"initial_reward": 50, "interval": 1000}}`.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Issuance {
+    /** The same reward forever. */
+    Fixed { reward:u128 },
+    /** The reward halves every `interval` blocks. An interval of 0 never halves. */
+    Halving { initial_reward:u128, interval:u32 },
+    /** The reward drops by `decrement` every block until it reaches 0. */
+    LinearDecay { initial_reward:u128, decrement:u128 }
+}
+
+impl Default for Issuance {
+    fn default() -> Self {
+        Issuance::Halving { initial_reward: COINBASE_REWARD, interval: HALVING_INTERVAL }
+    }
+}
+
+impl Issuance {
+    pub fn reward_at(&self, height:u32) -> u128 {
+        match *self {
+            Issuance::Fixed { reward } => reward,
+            Issuance::Halving { initial_reward, interval } => {
+                if interval == 0 { return initial_reward }
+                initial_reward.checked_shr(height / interval).unwrap_or(0)
+            }
+            Issuance::LinearDecay { initial_reward, decrement } =>
+                initial_reward.saturating_sub(decrement.saturating_mul(height as u128))
+        }
+    }
+
+    /** Total minted by the coinbases at heights `0..=height`. Saturates at `u128::MAX`. */
+    pub fn issued_through(&self, height:u32) -> u128 {
+        let blocks = height as u128 + 1;
+        match *self {
+            Issuance::Fixed { reward } => reward.saturating_mul(blocks),
+            Issuance::Halving { initial_reward, interval } => {
+                if interval == 0 { return initial_reward.saturating_mul(blocks) }
+                let mut total:u128 = 0;
+                let mut remaining = blocks;
+                let mut reward = initial_reward;
+                while remaining > 0 && reward > 0 {
+                    let epoch_blocks = remaining.min(interval as u128);
+                    total = total.saturating_add(reward.saturating_mul(epoch_blocks));
+                    remaining -= epoch_blocks;
+                    reward >>= 1;
+                }
+                total
+            }
+            Issuance::LinearDecay { initial_reward, decrement } => {
+                //only the first ceil(initial_reward / decrement) rewards are nonzero
+                let paying = if decrement == 0 { blocks } else { blocks.min(initial_reward.div_ceil(decrement)) };
+                let decayed = decrement.saturating_mul(paying.saturating_mul(paying.saturating_sub(1)) / 2);
+                initial_reward.saturating_mul(paying).saturating_sub(decayed)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Issuance;
+
+    #[test]
+    fn follows_the_schedule() {
+        let halving = Issuance::Halving { initial_reward: 50, interval: 10 };
+        assert_eq!([0, 9, 10, 20, 70].map(|height| halving.reward_at(height)), [50, 50, 25, 12, 0]);
+        assert_eq!(halving.issued_through(19), 750);
+        //50 halves to 0 after six halvings, so nothing more is ever minted
+        assert_eq!(halving.issued_through(1_000), halving.issued_through(u32::MAX));
+        let decay = Issuance::LinearDecay { initial_reward: 10, decrement: 3 };
+        assert_eq!([0, 1, 3, 4].map(|height| decay.reward_at(height)), [10, 7, 1, 0]);
+        assert_eq!(decay.issued_through(u32::MAX), 22);
+    }
+}
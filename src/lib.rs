@@ -3,13 +3,27 @@ extern crate core;
 use std::fmt;
 use std::ops::{Deref, DerefMut};
 use std::time::{SystemTime, UNIX_EPOCH };
-use hex::{encode, ToHex};
+use hex::{encode, decode};
+
+pub const HASH_LEN:usize = 32;
+pub const SIG_LEN:usize = 64;
+pub const PUBKEY_LEN:usize = 32;
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Hash)]
 pub struct Hash(Vec<u8>);
 impl Serialize for Hash {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
-        serializer.serialize_str(&*encode(self.as_hex()))
+        serializer.serialize_str(&self.as_hex())
+    }
+}
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let hex_str = String::deserialize(deserializer)?;
+        let bytes = decode(hex_str).map_err(de::Error::custom)?;
+        if bytes.len() != HASH_LEN {
+            return Err(de::Error::invalid_length(bytes.len(), &"a 32 byte hash"));
+        }
+        Ok(Hash(bytes))
     }
 }
 impl Deref for Hash {
@@ -29,19 +43,36 @@ impl Hash {
         encode(<Vec<u8> as AsRef<[u8]>>::as_ref(self))
     }
 }
-type Address = String;
 
-#[derive(Clone, Copy)]
-pub struct SigWrapper(Signature);
+/** Raw signature bytes. ring's `Signature` cannot be rebuilt from bytes, so the wrapper keeps them directly. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SigWrapper(Vec<u8>);
+
+impl From<Signature> for SigWrapper {
+    fn from(sig: Signature) -> Self {
+        SigWrapper(sig.as_ref().to_vec())
+    }
+}
 
 impl Serialize for SigWrapper {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
-        serializer.serialize_str(&*encode(self.as_ref()))
+        serializer.serialize_str(&encode(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for SigWrapper {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let hex_str = String::deserialize(deserializer)?;
+        let bytes = decode(hex_str).map_err(de::Error::custom)?;
+        if bytes.len() != SIG_LEN {
+            return Err(de::Error::invalid_length(bytes.len(), &"a 64 byte ed25519 signature"));
+        }
+        Ok(SigWrapper(bytes))
     }
 }
 
 impl Deref for SigWrapper {
-    type Target = Signature;
+    type Target = Vec<u8>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -73,66 +104,57 @@ pub fn now () -> u128 {
 }
 
 pub fn u16_to_bytes (u: &u16) -> [u8; 2] {
-    [
-        (u >> 8 * 0x0) as u8,
-        (u >> 8 * 0x1) as u8,
-    ]
+    u.to_le_bytes()
 }
 
 pub fn u32_to_bytes (u: &u32) -> [u8; 4] {
-    [
-        (u >> 8 * 0x0) as u8,
-        (u >> 8 * 0x1) as u8,
-        (u >> 8 * 0x2) as u8,
-        (u >> 8 * 0x3) as u8,
-    ]
+    u.to_le_bytes()
 }
 
 pub fn u64_to_bytes (u: &u64) -> [u8; 8] {
-    [
-        (u >> 8 * 0x0) as u8,
-        (u >> 8 * 0x1) as u8,
-        (u >> 8 * 0x2) as u8,
-        (u >> 8 * 0x3) as u8,
-        (u >> 8 * 0x4) as u8,
-        (u >> 8 * 0x5) as u8,
-        (u >> 8 * 0x6) as u8,
-        (u >> 8 * 0x7) as u8
-    ]
+    u.to_le_bytes()
 }
 
 pub fn u128_to_bytes (u: &u128) -> [u8; 16] {
-    [
-        (u >> 8 * 0x0) as u8,
-        (u >> 8 * 0x1) as u8,
-        (u >> 8 * 0x2) as u8,
-        (u >> 8 * 0x3) as u8,
-        (u >> 8 * 0x4) as u8,
-        (u >> 8 * 0x5) as u8,
-        (u >> 8 * 0x6) as u8,
-        (u >> 8 * 0x7) as u8,
-        (u >> 8 * 0x8) as u8,
-        (u >> 8 * 0x9) as u8,
-        (u >> 8 * 0xa) as u8,
-        (u >> 8 * 0xb) as u8,
-        (u >> 8 * 0xc) as u8,
-        (u >> 8 * 0xd) as u8,
-        (u >> 8 * 0xe) as u8,
-        (u >> 8 * 0xf) as u8
-    ]
+    u.to_le_bytes()
 }
 
+mod address;
+pub use crate::address::{Address, AddressError, AddressKind, MAINNET_HRP, TESTNET_HRP};
 mod block;
-pub use crate::block::Block;
+pub use crate::block::{Block, BlockHeader};
 mod utils;
 pub use crate::utils::*;
 mod client;
-pub use crate::client::Client;
+pub use crate::client::{Client, Reorg};
 mod miner;
-pub use crate::miner::Miner;
+pub use crate::miner::{Miner, MiningStats};
 mod transaction;
-pub use crate::transaction::Transaction;
+pub use crate::transaction::{Transaction, SIGNING_DOMAIN};
 mod blockchain;
-pub use crate::blockchain::Blockchain;
+pub use crate::blockchain::{block_work, Blockchain};
+mod error;
+pub use crate::error::ValidationError;
+pub mod net;
+mod mempool;
+pub use crate::mempool::{FeeRate, Mempool};
+mod merkle;
+pub use crate::merkle::{merkle_root, verify_merkle_proof, MerkleProof};
+mod storage;
+pub use crate::storage::{BlockStore, FileBlockStore, MemoryBlockStore};
+mod issuance;
+pub use crate::issuance::Issuance;
+mod encoding;
+pub use crate::encoding::{DecodeError, Decoder, Encoder, ENCODING_VERSION};
+mod state;
+pub use crate::state::{verify_account_proof, Account, AccountProof, AccountState};
+mod multisig;
+pub use crate::multisig::{MultisigPolicy, MAX_MULTISIG_KEYS};
+mod offline;
+pub use crate::offline::{SigningRequest, SIGNING_REQUEST_VERSION};
+mod wallet;
+pub use crate::wallet::{EncryptedKey, KdfParams, Keystore, WalletError, KEYSTORE_VERSION};
+mod hd;
+pub use crate::hd::{DerivationPath, HdAccount, HdError, HdWallet, DEFAULT_GAP_LIMIT, HD_COIN_TYPE, HD_PURPOSE};
 pub use ring::{digest, rand, signature::{self, Signature, KeyPair, Ed25519KeyPair}};
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
@@ -9,34 +9,47 @@ extern crate project_rusted_gold;
 
 
 
-fn gen_and_store_key() {
-    let rng = rand::SystemRandom::new();
-    let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
-    fs::write("./key", pkcs8_bytes.as_ref()).expect("Unable to write file");
-}
-
-fn get_key() -> Ed25519KeyPair {
-    let data = fs::read("./key").expect("Unable to read file");
-    Ed25519KeyPair::from_pkcs8(data.as_ref()).unwrap()
+const KEYSTORE_PATH:&str = "./wallet.json";
+const LEGACY_KEY_PATH:&str = "./key";
+const KEY_NAME:&str = "default";
+
+/** Opens the keystore, creating the default key on first run. A leftover unencrypted `./key`
+    is moved into the keystore and then removed.
+ */
+fn load_keystore(passphrase:&str) -> Result<Keystore, WalletError> {
+    let mut keystore = Keystore::open(KEYSTORE_PATH)?;
+    if keystore.address(KEY_NAME).is_err() {
+        match fs::read(LEGACY_KEY_PATH) {
+            Ok(pkcs8) => {
+                keystore.import_pkcs8(KEY_NAME, &pkcs8, passphrase)?;
+                fs::remove_file(LEGACY_KEY_PATH)?;
+            }
+            Err(_) => { keystore.create(KEY_NAME, passphrase)?; }
+        }
+    }
+    Ok(keystore)
 }
 
 fn main() {
     //let target = calc_pow_target();
     //println!("target:{}", encode(&*target));
-    let keypair = get_key();
-    let mut bryse = Client::new(String::from("Bryse"), None, Some(keypair));
-    let mut vianca = Client::new(String::from("Vianca"), None, None);
+    let passphrase = std::env::var("RUSTED_GOLD_PASSPHRASE").expect("set RUSTED_GOLD_PASSPHRASE to unlock the wallet");
+    let keystore = load_keystore(&passphrase).unwrap_or_else(|err| panic!("{}", err));
+    let blockchain = Blockchain::new();
+    let mut bryse = Client::from_keystore(String::from("Bryse"), blockchain.clone(), None, &keystore, KEY_NAME, &passphrase)
+        .unwrap_or_else(|err| panic!("{}", err));
+    let mut vianca = Client::new(String::from("Vianca"), blockchain.clone(), None, None);
 
 
-    let gen_block = Blockchain::make_genesis(
+    let gen_block = blockchain.make_genesis(
         BTreeMap::from([(bryse.address(), 20), (vianca.address(), 50)])
     );
 
     bryse.set_genesis(gen_block.clone());
     vianca.set_genesis(gen_block.clone());
-    let mut kj = Client::new(String::from("KJ"), Some(gen_block.clone()), None);
-    let mut grandma = Client::new(String::from("Grandma"), Some(gen_block.clone()), None);
-    let mut mr_miner = Miner::new(String::from("kevin"), Some(gen_block.clone()), None, None);
+    let mut kj = Client::new(String::from("KJ"), blockchain.clone(), Some(gen_block.clone()), None);
+    let mut grandma = Client::new(String::from("Grandma"), blockchain.clone(), Some(gen_block.clone()), None);
+    let mut mr_miner = Miner::new(String::from("kevin"), blockchain.clone(), Some(gen_block.clone()), None, None);
 
     // let mut block1 = Block::new(
     //     bryse.address(),
@@ -47,14 +60,14 @@ fn main() {
     println!("{} paying {} 5 gold and {} 5 gold", bryse.name, kj.name, grandma.name);
     let mut tx = bryse.post_transaction(vec![(kj.address(), 5), (grandma.address(), 5)], None).unwrap();
 
-    tx.sign(&bryse.keypair);
+    tx.sign(&bryse.keypair, &gen_block.id());
 
     println!("{} paying {} 10 gold and {} 5 gold", vianca.name, bryse.name, kj.name);
     let mut tx1 = vianca.post_transaction(vec![(bryse.address(), 10), (kj.address(), 5),], None).unwrap();
-    tx1.sign(&vianca.keypair);
+    tx1.sign(&vianca.keypair, &gen_block.id());
     println!("{} paying {} 5 gold", bryse.name, grandma.name);
     let mut tx2 = bryse.post_transaction(vec![(grandma.address(), 5)], None).unwrap();
-    tx2.sign(&bryse.keypair);
+    tx2.sign(&bryse.keypair, &gen_block.id());
     let tx_clone = tx.clone();
     let tx1_clone = tx1.clone();
     let tx2_clone = tx2.clone();
@@ -63,7 +76,7 @@ fn main() {
     mr_miner.add_transaction(tx2);
     mr_miner.initialize();
     for _ in 0..15{
-        mr_miner.find_proof();
+        mr_miner.mine_block();
     }
 
     mr_miner.client.show_all_balances();
@@ -0,0 +1,359 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter;
+use crate::{AccountState, Address, Block, Hash, Transaction, ValidationError};
+
+pub const DEFAULT_MEMPOOL_SIZE:usize = 5000;
+pub const DEFAULT_BLOCK_TRANSACTIONS:usize = 1000;
+
+/** Fee per byte of wire format, compared exactly by cross multiplying. */
+#[derive(Clone, Copy, Debug)]
+pub struct FeeRate {
+    pub fee:u128,
+    pub size:u128
+}
+
+impl FeeRate {
+    pub fn of(tx:&Transaction) -> Self {
+        FeeRate { fee: tx.fee as u128, size: tx.to_bytes().len() as u128 }
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other:&Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other:&Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other:&Self) -> Ordering {
+        (self.fee * other.size).cmp(&(other.fee * self.size))
+    }
+}
+
+/** Pending transactions waiting to be mined. */
+pub struct Mempool {
+    max_size:usize,
+    by_id:BTreeMap<Hash, Transaction>,
+    by_sender:BTreeMap<Address, BTreeMap<u128, Hash>>,
+    by_fee_rate:BTreeSet<(FeeRate, Hash)>,
+    /** State of the tip last passed to `prune`, to tell which txs could be mined next and what their senders can pay. */
+    tip:AccountState,
+    tip_id:Option<Hash>
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new(DEFAULT_MEMPOOL_SIZE)
+    }
+}
+
+impl Mempool {
+    pub fn new(max_size:usize) -> Self {
+        Mempool {
+            max_size: max_size.max(1),
+            by_id: BTreeMap::new(),
+            by_sender: BTreeMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            tip: AccountState::new(),
+            tip_id: None
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_id.is_empty()
+    }
+
+    pub fn contains(&self, tx_id:&Hash) -> bool {
+        self.by_id.contains_key(tx_id)
+    }
+
+    pub fn get(&self, tx_id:&Hash) -> Option<&Transaction> {
+        self.by_id.get(tx_id)
+    }
+
+    pub fn get_by_nonce(&self, from:&Address, nonce:u128) -> Option<&Transaction> {
+        let id = self.by_sender.get(from)?.get(&nonce)?;
+        self.by_id.get(id)
+    }
+
+    /** Highest fee rate first. */
+    pub fn by_fee_rate(&self) -> Vec<&Transaction> {
+        self.by_fee_rate.iter().rev().map(|(_, id)| &self.by_id[id]).collect()
+    }
+
+    /** Adds `tx`. A tx reusing a pending sender/nonce replaces it only if it pays a higher fee.
+        The sender's balance at the tip has to cover `tx` and all of its other pending txs.
+        When full, txs that can't be mined next are evicted first, then the lowest fee rate tx
+        without pending descendants. Either may be `tx` itself.
+     */
+    pub fn insert(&mut self, tx:Transaction) -> Result<(), ValidationError> {
+        let tx_id = tx.id();
+        if self.by_id.contains_key(&tx_id) {
+            return Err(ValidationError::DuplicateTx(tx_id));
+        }
+        let next_nonce = self.tip.nonce_of(&tx.from);
+        if tx.nonce < next_nonce {
+            return Err(ValidationError::ReplayedNonce { expected: next_nonce, got: tx.nonce });
+        }
+        let available = self.tip.balance_of(&tx.from);
+        let required = self.pending_cost(&tx)?;
+        if required.is_none_or(|required| required > available) {
+            return Err(ValidationError::InsufficientFunds { address: tx.from.clone(), available, required: required.unwrap_or(u128::MAX) });
+        }
+        if let Some(existing) = self.get_by_nonce(&tx.from, tx.nonce) {
+            if existing.fee >= tx.fee {
+                return Err(ValidationError::FeeTooLow { tx_id, required: existing.fee as u128 + 1 });
+            }
+            let existing_id = existing.id();
+            self.remove(&existing_id);
+        }
+        self.by_sender.entry(tx.from.clone()).or_default().insert(tx.nonce, tx_id.clone());
+        self.by_fee_rate.insert((FeeRate::of(&tx), tx_id.clone()));
+        self.by_id.insert(tx_id.clone(), tx);
+
+        while self.by_id.len() > self.max_size {
+            let evicted = match self.eviction_candidate() {
+                Some(id) => id,
+                None => break
+            };
+            let fee = self.by_id[&evicted].fee;
+            self.remove(&evicted);
+            if evicted == tx_id {
+                return Err(ValidationError::FeeTooLow { tx_id, required: fee as u128 + 1 });
+            }
+        }
+        Ok(())
+    }
+
+    // what `tx` and the rest of its sender's pending txs, less any `tx` replaces, spend together. None if that overflows.
+    fn pending_cost(&self, tx:&Transaction) -> Result<Option<u128>, ValidationError> {
+        let mut required = Some(tx.total_output()?);
+        if let Some(nonces) = self.by_sender.get(&tx.from) {
+            for (_, id) in nonces.iter().filter(|(nonce, _)| **nonce != tx.nonce) {
+                let cost = self.by_id[id].total_output().ok();
+                required = required.zip(cost).and_then(|(required, cost)| required.checked_add(cost));
+            }
+        }
+        Ok(required)
+    }
+
+    // lowest fee rate tx stuck behind a missing nonce, or failing that the lowest fee rate tx
+    // that no other pending tx depends on
+    fn eviction_candidate(&self) -> Option<Hash> {
+        let mut minable_through:BTreeMap<&Address, u128> = BTreeMap::new();
+        for from in self.by_sender.keys() {
+            if let Some(last) = self.chain_from(from, self.tip.nonce_of(from)).last() {
+                minable_through.insert(from, last.nonce);
+            }
+        }
+        let by_rate = || self.by_fee_rate.iter().map(|(_, id)| (id, &self.by_id[id]));
+        by_rate()
+            .find(|(_, tx)| minable_through.get(&tx.from).is_none_or(|last| tx.nonce > *last))
+            .or_else(|| by_rate().find(|(_, tx)| {
+                tx.nonce.checked_add(1).is_none_or(|next| self.get_by_nonce(&tx.from, next).is_none())
+            }))
+            .map(|(id, _)| id.clone())
+    }
+
+    pub fn remove(&mut self, tx_id:&Hash) -> Option<Transaction> {
+        let tx = self.by_id.remove(tx_id)?;
+        self.by_fee_rate.remove(&(FeeRate::of(&tx), tx_id.clone()));
+        if let Some(nonces) = self.by_sender.get_mut(&tx.from) {
+            nonces.remove(&tx.nonce);
+            if nonces.is_empty() {
+                self.by_sender.remove(&tx.from);
+            }
+        }
+        Some(tx)
+    }
+
+    /** Drops everything already mined or replaced as of `tip`, which inserts and eviction then treat as the chain tip. */
+    pub fn prune(&mut self, tip:&Block) {
+        self.tip = tip.state.clone();
+        self.tip_id = Some(tip.id());
+        let mut stale = vec![];
+        for (from, nonces) in &self.by_sender {
+            let next_nonce = tip.nonce_of(from);
+            stale.extend(nonces.range(..next_nonce).map(|(_, id)| id.clone()));
+        }
+        for id in stale {
+            self.remove(&id);
+        }
+    }
+
+    /** Whether `prune` was last called with `block`. */
+    pub fn is_pruned_to(&self, block:&Block) -> bool {
+        self.tip_id.as_ref() == Some(&block.id())
+    }
+
+    /** The consecutive pending txs of `from` starting at `nonce`. */
+    fn chain_from(&self, from:&Address, nonce:u128) -> Vec<&Transaction> {
+        let mut chain = vec![];
+        if let Some(nonces) = self.by_sender.get(from) {
+            let expected = iter::successors(Some(nonce), |nonce| nonce.checked_add(1));
+            for ((nonce, id), expected) in nonces.range(nonce..).zip(expected) {
+                if *nonce != expected { break }
+                chain.push(&self.by_id[id]);
+            }
+        }
+        chain
+    }
+
+    /** `from`'s best package given `block`: the run of its next txs, at most `room` long,
+        with the highest average fee rate, and that run's length.
+     */
+    fn best_package(&self, block:&Block, from:&Address, room:usize) -> Option<(FeeRate, usize)> {
+        let mut best:Option<(FeeRate, usize)> = None;
+        let mut package = FeeRate { fee: 0, size: 0 };
+        for (i, tx) in self.chain_from(from, block.nonce_of(from)).iter().take(room).enumerate() {
+            let rate = FeeRate::of(tx);
+            package = FeeRate { fee: package.fee + rate.fee, size: package.size + rate.size };
+            if best.as_ref().is_none_or(|(best_rate, _)| package > *best_rate) {
+                best = Some((package, i + 1));
+            }
+        }
+        best
+    }
+
+    /** Adds the most profitable txs to `block`, up to `max_txs`.
+        A sender's txs go in nonce order, so each sender is scored by the best average
+        fee rate of a run of its txs, letting a high fee child pay for a cheap parent.
+        Txs that can never be mined, or that their sender can't pay for at the tip, are dropped from the pool.
+     */
+    pub fn fill_block(&mut self, block:&mut Block, max_txs:usize) -> usize {
+        let mut added = 0;
+        //scores only change for the sender whose package went in, or once the room left gets short
+        let mut packages:BTreeMap<Address, (FeeRate, usize)> = BTreeMap::new();
+        for from in self.by_sender.keys() {
+            if let Some(package) = self.best_package(block, from, max_txs) {
+                packages.insert(from.clone(), package);
+            }
+        }
+        while added < max_txs {
+            let room = max_txs - added;
+            let too_long:Vec<Address> = packages.iter()
+                .filter(|(_, (_, count))| *count > room)
+                .map(|(from, _)| from.clone())
+                .collect();
+            for from in too_long {
+                match self.best_package(block, &from, room) {
+                    Some(package) => packages.insert(from, package),
+                    None => packages.remove(&from)
+                };
+            }
+            let mut best:Option<(&Address, FeeRate, usize)> = None;
+            for (from, (rate, count)) in &packages {
+                if best.as_ref().is_none_or(|(_, best_rate, _)| rate > best_rate) {
+                    best = Some((from, *rate, *count));
+                }
+            }
+            let (from, count) = match best {
+                Some((from, _, count)) => (from.clone(), count),
+                None => break
+            };
+            let package:Vec<Transaction> = self.chain_from(&from, block.nonce_of(&from)).into_iter().take(count).cloned().collect();
+            let mut skipped = false;
+            for tx in package {
+                let tx_id = tx.id();
+                let tx_cost = tx.total_output().ok();
+                match block.add_transaction(tx) {
+                    Ok(()) => added += 1,
+                    Err(err) => {
+                        //short only because of this block's earlier txs, it may fit in a later one
+                        let affordable = tx_cost.is_some_and(|cost| cost <= self.tip.balance_of(&from));
+                        if !matches!(err, ValidationError::InsufficientFunds { .. }) || !affordable {
+                            self.remove(&tx_id);
+                        }
+                        skipped = true;
+                        break;
+                    }
+                }
+            }
+            //a sender whose whole pending chain made it in has nothing left to offer
+            match self.best_package(block, &from, max_txs - added).filter(|_| !skipped) {
+                Some(package) => packages.insert(from, package),
+                None => packages.remove(&from)
+            };
+        }
+        added
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use crate::{calc_address, Address, Block, Blockchain, MemoryBlockStore, Transaction, ValidationError};
+    use super::Mempool;
+
+    fn keypair(seed:u8) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap()
+    }
+
+    fn address(seed:u8) -> Address {
+        calc_address(keypair(seed).public_key().as_ref())
+    }
+
+    fn tx(seed:u8, nonce:u128, amount:u128, fee:u32, chain:&Block) -> Transaction {
+        let keypair = keypair(seed);
+        let mut tx = Transaction::new(address(seed), nonce, keypair.public_key().as_ref().to_vec(), vec![(address(9), amount)], fee, String::new());
+        tx.sign(&keypair, &chain.chain_id());
+        tx
+    }
+
+    fn chain() -> Blockchain {
+        Blockchain::new().with_pow_leading_zeros(0).with_retarget_interval(0)
+    }
+
+    #[test]
+    fn unfunded_senders_cant_fill_the_pool() {
+        let genesis = chain().make_genesis(BTreeMap::from([(address(1), 100)]));
+        let mut mempool = Mempool::new(3);
+        mempool.prune(&genesis);
+        for seed in 2..5 {
+            assert!(matches!(mempool.insert(tx(seed, 0, 0, u32::MAX, &genesis)), Err(ValidationError::InsufficientFunds { .. })));
+        }
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.insert(tx(1, 0, 50, 1, &genesis)), Ok(()));
+        //the sender's pending txs are paid for together
+        assert!(matches!(mempool.insert(tx(1, 1, 50, 1, &genesis)), Err(ValidationError::InsufficientFunds { .. })));
+        assert_eq!(mempool.insert(tx(1, 1, 48, 1, &genesis)), Ok(()));
+        //replacing a tx frees what it would have spent
+        assert_eq!(mempool.insert(tx(1, 0, 10, 2, &genesis)), Ok(()));
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn drops_txs_the_tip_cant_pay_for() {
+        let chain = chain();
+        let store = MemoryBlockStore::new();
+        let genesis = chain.make_genesis(BTreeMap::from([(address(1), 100)]));
+        let mut pay = tx(1, 0, 50, 1, &genesis);
+        pay.outputs = vec![(address(2), 50)];
+        pay.sign(&keypair(1), &genesis.chain_id());
+        let mut funded = chain.make_block(address(3), &genesis, &store).unwrap();
+        funded.add_transaction(pay).unwrap();
+
+        let mut mempool = Mempool::default();
+        mempool.prune(&funded);
+        assert_eq!(mempool.insert(tx(2, 0, 40, 1, &genesis)), Ok(()));
+        //a reorg onto a branch where the payment never happened
+        mempool.prune(&genesis);
+        let mut block = chain.make_block(address(3), &genesis, &store).unwrap();
+        assert_eq!(mempool.fill_block(&mut block, 10), 0);
+        assert!(mempool.is_empty());
+    }
+}
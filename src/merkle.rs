@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+use crate::{hash, Hash, HASH_LEN};
+
+//domain separation, so a leaf can't pass for a branch or the root, or the other way round
+const LEAF_PREFIX:u8 = 0;
+const BRANCH_PREFIX:u8 = 1;
+const ROOT_PREFIX:u8 = 2;
+
+fn leaf_hash(leaf:&Hash) -> Hash {
+    let mut bytes = Vec::with_capacity(1 + HASH_LEN);
+    bytes.push(LEAF_PREFIX);
+    bytes.extend_from_slice(leaf);
+    Hash(hash(&bytes))
+}
+
+fn branch_hash(left:&Hash, right:&Hash) -> Hash {
+    let mut bytes = Vec::with_capacity(1 + 2 * HASH_LEN);
+    bytes.push(BRANCH_PREFIX);
+    bytes.extend_from_slice(left);
+    bytes.extend_from_slice(right);
+    Hash(hash(&bytes))
+}
+
+// commits to how many leaves there are, so a proof can't claim a position past the last one
+fn root_hash(leaf_count:usize, top:&Hash) -> Hash {
+    let mut bytes = Vec::with_capacity(1 + 8 + HASH_LEN);
+    bytes.push(ROOT_PREFIX);
+    bytes.extend_from_slice(&(leaf_count as u64).to_be_bytes());
+    bytes.extend_from_slice(top);
+    Hash(hash(&bytes))
+}
+
+fn next_level(level:&[Hash]) -> Vec<Hash> {
+    level.chunks(2)
+        .map(|pair| branch_hash(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+/** Root of a binary Merkle tree over `leaves`, committing to their number. An odd node out is paired with itself. */
+pub fn merkle_root(leaves:&[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return Hash(vec![0; HASH_LEN]);
+    }
+    let mut level:Vec<Hash> = leaves.iter().map(leaf_hash).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    root_hash(leaves.len(), &level[0])
+}
+
+/** Sibling hashes from a leaf up to the root. Bit `i` of `index` says whether the
+    node at level `i` is a right child. `leaf_count` is part of the root, so it can be trusted once the proof checks out.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub index:usize,
+    pub leaf_count:usize,
+    pub siblings:Vec<Hash>
+}
+
+/** Inclusion proof for `leaves[index]`, or None if the index is out of range. */
+pub fn merkle_proof(leaves:&[Hash], index:usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut siblings = vec![];
+    let mut level:Vec<Hash> = leaves.iter().map(leaf_hash).collect();
+    let mut position = index;
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        siblings.push(level.get(sibling).unwrap_or(&level[position]).clone());
+        level = next_level(&level);
+        position /= 2;
+    }
+    Some(MerkleProof { index, leaf_count: leaves.len(), siblings })
+}
+
+pub fn verify_merkle_proof(root:&Hash, leaf:&Hash, proof:&MerkleProof) -> bool {
+    if proof.index >= proof.leaf_count {
+        return false;
+    }
+    let mut node = leaf_hash(leaf);
+    let mut position = proof.index;
+    let mut width = proof.leaf_count;
+    let mut siblings = proof.siblings.iter();
+    while width > 1 {
+        let sibling = match siblings.next() {
+            Some(sibling) => sibling,
+            None => return false
+        };
+        //the odd node out is paired with itself and nothing else
+        if position == width - 1 && width % 2 == 1 && *sibling != node {
+            return false;
+        }
+        node = if position & 1 == 0 { branch_hash(&node, sibling) } else { branch_hash(sibling, &node) };
+        position /= 2;
+        width = width.div_ceil(2);
+    }
+    siblings.next().is_none() && root_hash(proof.leaf_count, &node) == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merkle_proof, merkle_root, next_level, leaf_hash, verify_merkle_proof, MerkleProof};
+    use crate::{hash, Hash};
+
+    fn leaves(count:u8) -> Vec<Hash> {
+        (0..count).map(|i| Hash(hash(&[i]))).collect()
+    }
+
+    #[test]
+    fn proves_every_leaf() {
+        for count in 1..=7 {
+            let leaves = leaves(count);
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                assert!(verify_merkle_proof(&root, leaf, &merkle_proof(&leaves, index).unwrap()));
+            }
+            assert_eq!(merkle_proof(&leaves, leaves.len()), None);
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_leaves_inner_nodes_and_phantom_positions() {
+        let leaves = leaves(3);
+        let root = merkle_root(&leaves);
+        let proof = merkle_proof(&leaves, 0).unwrap();
+        assert!(!verify_merkle_proof(&root, &leaves[1], &proof));
+        //the branch over the first two leaves, passed off as a leaf one level up
+        let inner = next_level(&leaves.iter().map(leaf_hash).collect::<Vec<Hash>>())[0].clone();
+        let shorter = MerkleProof { index: 0, leaf_count: 2, siblings: proof.siblings[1..].to_vec() };
+        assert!(!verify_merkle_proof(&root, &inner, &shorter));
+        //the last leaf again, in the slot it is paired with
+        let last = merkle_proof(&leaves, 2).unwrap();
+        assert!(!verify_merkle_proof(&root, &leaves[2], &MerkleProof { index: 3, ..last.clone() }));
+        assert!(!verify_merkle_proof(&root, &leaves[2], &MerkleProof { index: 3, leaf_count: 4, ..last }));
+    }
+}
@@ -1,133 +1,253 @@
-use std::fmt::format;
-use std::ops::Add;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 use ring::signature::Ed25519KeyPair;
-use crate::{Address, Block, Blockchain, Client, Hash, Transaction};
-use crate::blockchain::DEFAULT_MINING_ROUNDS;
+use crate::{Address, Block, BlockHeader, Blockchain, Client, Mempool, Reorg, Transaction, ValidationError};
+use crate::mempool::DEFAULT_BLOCK_TRANSACTIONS;
+use crate::blockchain::{DEFAULT_MINING_ROUNDS, DEFAULT_MINING_WORKERS};
+
+/** Hashing totals since the miner was created. */
+#[derive(Clone, Debug, Default)]
+pub struct MiningStats {
+    pub hashes:u128,
+    pub elapsed:Duration,
+    pub blocks_found:u32
+}
+
+impl MiningStats {
+    /** Hashes per second. */
+    pub fn hash_rate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 { 0.0 } else { self.hashes as f64 / secs }
+    }
+}
 
 pub struct Miner {
     pub client: Client,
-    transactions: Vec<Transaction>,
+    mempool: Mempool,
+    max_block_transactions:usize,
     current_block:Option<Block>,
-    mining_rounds:usize
+    mining_rounds:usize,
+    workers:usize,
+    stats:MiningStats
+}
+
+/** Tries every proof in `start..end` on `workers` threads, worker `i` taking the proofs
+    congruent to `i` mod `workers`. All workers stop as soon as one of them hits.
+    Returns the winning proof, if any, and how many hashes were tried.
+ */
+fn search_proofs(header:&BlockHeader, start:u128, end:u128, workers:usize) -> (Option<u128>, u128) {
+    let found = AtomicBool::new(false);
+    let winner:Mutex<Option<u128>> = Mutex::new(None);
+    let hashes = AtomicU64::new(0);
+    thread::scope(|scope| {
+        for worker in 0..workers {
+            let (found, winner, hashes) = (&found, &winner, &hashes);
+            let mut header = header.clone();
+            scope.spawn(move || {
+                let mut tried:u64 = 0;
+                let mut proof = start + worker as u128;
+                while proof < end && !found.load(Ordering::Relaxed) {
+                    header.proof = proof;
+                    tried += 1;
+                    if header.has_valid_proof() {
+                        if !found.swap(true, Ordering::Relaxed) {
+                            *winner.lock().unwrap() = Some(proof);
+                        }
+                        break;
+                    }
+                    proof += workers as u128;
+                }
+                hashes.fetch_add(tried, Ordering::Relaxed);
+            });
+        }
+    });
+    let winner = *winner.lock().unwrap();
+    (winner, hashes.into_inner() as u128)
 }
 
 impl Miner {
-    pub fn new(name: String, starting_block:Option<Block>, keypair:Option<Ed25519KeyPair>, mining_rounds:Option<usize>) -> Self {
+    pub fn new(name: String, blockchain:Blockchain, starting_block:Option<Block>, keypair:Option<Ed25519KeyPair>, mining_rounds:Option<usize>) -> Self {
+        Miner::from_client(Client::new(name, blockchain, starting_block, keypair), mining_rounds)
+    }
+
+    /** Mines on behalf of an existing client, e.g. one restored with `Client::with_store`. */
+    pub fn from_client(client:Client, mining_rounds:Option<usize>) -> Self {
         Miner {
-            client: Client::new(name, starting_block, keypair),
-            transactions: vec![],
+            client,
+            mempool: Mempool::default(),
+            max_block_transactions: DEFAULT_BLOCK_TRANSACTIONS,
             current_block: None,
-            mining_rounds: mining_rounds.unwrap_or(DEFAULT_MINING_ROUNDS)
+            mining_rounds: mining_rounds.unwrap_or(DEFAULT_MINING_ROUNDS),
+            workers: DEFAULT_MINING_WORKERS,
+            stats: MiningStats::default()
         }
     }
 
+    /** Number of threads `find_proof` splits the proof space across. */
+    pub fn with_workers(mut self, workers:usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    pub fn with_mempool_size(mut self, max_size:usize) -> Self {
+        self.mempool = Mempool::new(max_size);
+        self
+    }
+
+    /** Most transactions this miner puts in one block. */
+    pub fn with_max_block_transactions(mut self, max_block_transactions:usize) -> Self {
+        self.max_block_transactions = max_block_transactions;
+        self
+    }
+
+    pub fn mempool(&self) -> &Mempool {
+        &self.mempool
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    pub fn stats(&self) -> &MiningStats {
+        &self.stats
+    }
+
     pub fn initialize (&mut self) {
         self.start_new_search(None);
     }
 
     //inefficient
     pub fn start_new_search (&mut self, tx_set: Option<Vec<Transaction>>) {
-        if self.last_block().is_some() {
-            self.current_block = Some(Blockchain::make_block(self.address(), &self.last_block().unwrap()));
-            if tx_set.is_some() {
-                for tx in tx_set.unwrap().iter() {
-                    self.transactions.push(tx.clone());
-                }
-            }
-            for tx in &self.transactions{
-                self.current_block.as_mut().unwrap().add_transaction(tx.clone());
-            }
-            self.transactions.clear();
-            self.current_block.as_mut().unwrap().proof = 0;
-        }
-        else {
-            panic!("Trying to mine without a genesis block.")
+        let last_block = match self.last_block() {
+            Some(block) => block,
+            None => panic!("Trying to mine without a genesis block.")
+        };
+        self.mempool.prune(&last_block);
+        for tx in tx_set.unwrap_or_default() {
+            //already pending or already mined is fine here
+            let _ = self.mempool.insert(tx);
         }
+        let mut block = match self.client.blockchain.make_block(self.address(), &last_block, &*self.client.blocks) {
+            Ok(block) => block,
+            Err(err) => panic!("Can't extend the chain: {}", err)
+        };
+        self.mempool.fill_block(&mut block, self.max_block_transactions);
+        block.header.proof = 0;
+        self.current_block = Some(block);
     }
 
-    pub fn find_proof(&mut self) {
+    /** Tries the next `mining_rounds` proofs on the current block, then returns so incoming
+        blocks and transactions can be handled. The next call picks up where this one stopped.
+        Returns the block once a proof is found and the client accepts it.
+     */
+    pub fn find_proof(&mut self) -> Option<Block> {
         if self.current_block.is_some() {
-            //let pause_point = self.current_block.as_ref().unwrap().proof + self.mining_rounds as u128;
-            let pause_point = u128::MAX;
-            while self.current_block.as_ref().unwrap().proof < pause_point {
-                if self.current_block.as_ref().unwrap().has_valid_proof() {
-                    self.log(&format!("found proof for block {}: {}", self.current_block.as_ref().unwrap().chain_length, self.current_block.as_ref().unwrap().proof));
-                    //self.announce_proof();
-                    self.receive_block(self.current_block.as_ref().unwrap().clone());
-                    break;
+            let header = self.current_block.as_ref().unwrap().header.clone();
+            let pause_point = header.proof.saturating_add(self.mining_rounds as u128);
+            let started = Instant::now();
+            let (proof, hashes) = search_proofs(&header, header.proof, pause_point, self.workers);
+            self.stats.hashes += hashes;
+            self.stats.elapsed += started.elapsed();
+            let proof = match proof {
+                Some(proof) => proof,
+                None => {
+                    self.current_block.as_mut().unwrap().header.proof = pause_point;
+                    return None;
+                }
+            };
+            self.current_block.as_mut().unwrap().header.proof = proof;
+            self.stats.blocks_found += 1;
+            self.log(&format!("found proof for block {}: {} ({:.0} H/s)", header.chain_length, proof, self.stats.hash_rate()));
+            match self.receive_block(self.current_block.as_ref().unwrap().clone()) {
+                Ok(block) => Some(block),
+                Err(err) => {
+                    self.log(&format!("Own block rejected: {}", err));
+                    //start over rather than finding the same proof again
+                    self.start_new_search(None);
+                    None
                 }
-                self.current_block.as_mut().unwrap().proof += 1;
             }
         }
         else { panic!("trying to find proof before setting current block"); }
 
     }
 
-    fn announce_proof () {
-        todo!()
+    /** Runs batches until this miner extends the chain. */
+    pub fn mine_block(&mut self) -> Block {
+        loop {
+            if let Some(block) = self.find_proof() {
+                return block;
+            }
+        }
     }
 
-    fn receive_block (&mut self, incoming_block:Block) -> Option<Block> {
-
-        match self.client.receive_block(incoming_block) {
-            None => return None,
-            Some(block) => {
-                let current_block = self.current_block.as_ref();
-                if current_block.is_some() && current_block.unwrap().chain_length <= block.chain_length {
-                    self.log("Cutting over to new chain.");
-                    let tx_set:Option<Vec<Transaction>>;
-                    let unincluded_txs = self.sync_transactions(block.clone());
-                    if unincluded_txs.is_empty() {tx_set = None}
-                    else {tx_set = Some(unincluded_txs)}
-                    self.start_new_search(tx_set);
-                }
-                Some(block)
+    pub fn receive_block (&mut self, incoming_block:Block) -> Result<Block, ValidationError> {
+        let block = self.client.receive_block(incoming_block)?;
+        if let Some(reorg) = self.client.take_reorg() {
+            if !reorg.disconnected.is_empty() {
+                self.log(&format!("Reorganized: {} blocks disconnected, {} connected.", reorg.disconnected.len(), reorg.connected.len()));
             }
+            self.log("Cutting over to new chain.");
+            let disconnected_txs = self.disconnected_transactions(&reorg);
+            let tx_set = if disconnected_txs.is_empty() {None} else {Some(disconnected_txs)};
+            self.start_new_search(tx_set);
         }
+        Ok(block)
     }
 
-    fn sync_transactions(&mut self, block: Block) -> Vec<Transaction> {
-        let mut new_block = &block;
-        let mut current_block:Option<&Block> = Some(self.current_block.as_ref().unwrap());
-        let mut new_block_txs:Vec<&Hash> = vec![];
-        let mut cur_block_txs:Vec<Transaction> = vec![];
-
-        while new_block.chain_length > current_block.unwrap().chain_length {
-            for (id,_) in &new_block.transactions {
-                new_block_txs.push(id);
+    pub fn receive_transaction(&mut self, mut tx:Transaction) -> Result<(), ValidationError> {
+        tx.set_network(self.client.blockchain.address_hrp());
+        if !tx.is_signed() { return Err(ValidationError::MissingSignature) }
+        tx.check_sender()?;
+        tx.check_outputs()?;
+        if let Some(last_block) = self.last_block() {
+            tx.check_signatures(&last_block.chain_id())?;
+            let next_nonce = last_block.nonce_of(&tx.from);
+            if tx.nonce < next_nonce {
+                return Err(ValidationError::ReplayedNonce { expected: next_nonce, got: tx.nonce });
             }
-            new_block = self.client.blocks.get(&new_block.prev_block_hash).as_ref().unwrap();
         }
+        self.sync_mempool();
+        self.mempool.insert(tx)
+    }
 
-        while current_block.is_some() && current_block.unwrap().id() != new_block.id() {
-            for (_,tx) in &current_block.unwrap().transactions {
-                cur_block_txs.push(tx.clone());
-            }
-            for (id,_) in &new_block.transactions {
-                new_block_txs.push(id);
+    // checks of new txs go against the client's current tip, whatever moved it
+    fn sync_mempool(&mut self) {
+        if let Some(last_block) = self.last_block() {
+            if !self.mempool.is_pruned_to(&last_block) {
+                self.mempool.prune(&last_block);
             }
-            current_block = self.client.blocks.get(&current_block.unwrap().prev_block_hash);
-            new_block = self.client.blocks.get(&new_block.prev_block_hash).unwrap();
         }
-
-        cur_block_txs.retain(|tx| new_block_txs.contains(&&tx.id()));
-        cur_block_txs
-
-
     }
 
-    pub fn add_transaction(&mut self, tx:Transaction) {
-        //will need to deserialize when network implemented
-        self.transactions.push(tx);
+    /** Transactions from blocks that left the main chain. They go back into the mempool. */
+    fn disconnected_transactions(&self, reorg:&Reorg) -> Vec<Transaction> {
+        let mut txs:Vec<Transaction> = vec![];
+        for id in &reorg.disconnected {
+            if let Some(block) = self.client.blocks.get(id) {
+                txs.extend(block.transactions.into_values());
+            }
+        }
+        txs
     }
 
-    pub fn post_transaction(&mut self, outputs:Vec<(Address, u128)>, custom_fee:Option<u32>) {
-        let tx = self.client.post_transaction(outputs, custom_fee);
-        if tx.is_some() {
-            self.add_transaction(tx.unwrap())
+    pub fn add_transaction(&mut self, mut tx:Transaction) {
+        tx.set_network(self.client.blockchain.address_hrp());
+        let tx_id = tx.id();
+        self.sync_mempool();
+        if let Err(err) = self.mempool.insert(tx) {
+            self.log(&format!("Not adding tx {}: {}", tx_id.as_hex(), err));
         }
     }
 
+    pub fn post_transaction(&mut self, outputs:Vec<(Address, u128)>, custom_fee:Option<u32>) -> Option<Transaction> {
+        let tx = self.client.post_transaction(outputs, custom_fee)?;
+        self.add_transaction(tx.clone());
+        Some(tx)
+    }
+
     pub fn log(&self, msg:&str) {
         self.client.log(msg);
     }
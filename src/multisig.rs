@@ -0,0 +1,130 @@
+use serde::{de, Deserialize, Deserializer, Serialize};
+use crate::encoding::{DecodeError, Decoder, Encoder};
+use crate::{hash, Address, Hash, ValidationError, PUBKEY_LEN};
+
+/** Most keys a multisig account can have. */
+pub const MAX_MULTISIG_KEYS:usize = 16;
+
+/** An M-of-N account: any `threshold` of `keys` can spend from it. The keys are kept sorted,
+    so the same set of keys and threshold always gives the same address.
+ */
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct MultisigPolicy {
+    threshold:u8,
+    keys:Vec<Vec<u8>>
+}
+
+impl MultisigPolicy {
+    /** Errors unless every key is a 32 byte public key and `1 <= threshold <= keys.len() <= MAX_MULTISIG_KEYS`
+        once duplicate keys are dropped.
+     */
+    pub fn new(threshold:u8, mut keys:Vec<Vec<u8>>) -> Result<Self, ValidationError> {
+        keys.sort();
+        keys.dedup();
+        let policy = MultisigPolicy { threshold, keys };
+        policy.check().map_err(ValidationError::InvalidMultisig)?;
+        Ok(policy)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.keys.len() > MAX_MULTISIG_KEYS {
+            return Err(format!("{} keys, at most {} allowed", self.keys.len(), MAX_MULTISIG_KEYS));
+        }
+        if self.threshold == 0 || usize::from(self.threshold) > self.keys.len() {
+            return Err(format!("threshold {} of {} keys", self.threshold, self.keys.len()));
+        }
+        if self.keys.iter().any(|key| key.len() != PUBKEY_LEN) {
+            return Err("keys must be 32 byte ed25519 public keys".to_string());
+        }
+        if self.keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err("keys must be sorted and distinct".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    pub fn keys(&self) -> &[Vec<u8>] {
+        &self.keys
+    }
+
+    /** Position of `public_key` in `keys`. Signatures are tagged with it. */
+    pub fn index_of(&self, public_key:&[u8]) -> Option<u8> {
+        self.keys.iter().position(|key| key == public_key).map(|index| index as u8)
+    }
+
+    pub(crate) fn encode(&self, encoder:&mut Encoder) {
+        encoder.u8(self.threshold);
+        encoder.count(self.keys.len());
+        for key in &self.keys {
+            encoder.fixed(key);
+        }
+    }
+
+    pub(crate) fn decode(decoder:&mut Decoder) -> Result<Self, DecodeError> {
+        let threshold = decoder.u8()?;
+        let mut keys = vec![];
+        for _ in 0..decoder.count()? {
+            keys.push(decoder.fixed(PUBKEY_LEN)?);
+        }
+        let policy = MultisigPolicy { threshold, keys };
+        policy.check().map_err(DecodeError::Invalid)?;
+        Ok(policy)
+    }
+
+    /** The address gold is sent to. It commits to the threshold and every key. */
+    pub fn address(&self) -> Address {
+        let mut encoder = Encoder::new();
+        self.encode(&mut encoder);
+        Address::from_multisig_hash(&Hash(hash(&encoder.finish())))
+    }
+}
+
+impl<'de> Deserialize<'de> for MultisigPolicy {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Fields {
+            threshold:u8,
+            keys:Vec<Vec<u8>>
+        }
+        let fields = Fields::deserialize(deserializer)?;
+        let policy = MultisigPolicy { threshold: fields.threshold, keys: fields.keys };
+        policy.check().map_err(de::Error::custom)?;
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use crate::{calc_address, Address, Blockchain, MemoryBlockStore, Transaction, ValidationError};
+    use super::MultisigPolicy;
+
+    #[test]
+    fn needs_threshold_signatures_from_its_keys() {
+        let keys:Vec<Ed25519KeyPair> = (1..=3).map(|seed| Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap()).collect();
+        let policy = MultisigPolicy::new(2, keys.iter().map(|key| key.public_key().as_ref().to_vec()).collect()).unwrap();
+        assert!(MultisigPolicy::new(4, policy.keys().to_vec()).is_err());
+        let chain = Blockchain::new().with_pow_leading_zeros(0).with_retarget_interval(0);
+        let genesis = chain.make_genesis(BTreeMap::from([(policy.address(), 100)]));
+        let chain_id = genesis.chain_id();
+        let mut tx = Transaction::new_multisig(policy.clone(), 0, vec![(Address::from_public_key(&[9; 32]), 10)], 1, String::new());
+
+        let outsider = Ed25519KeyPair::from_seed_unchecked(&[4; 32]).unwrap();
+        assert_eq!(tx.cosign(&outsider, &chain_id), Err(ValidationError::NotACosigner(calc_address(outsider.public_key().as_ref()))));
+        tx.cosign(&keys[0], &chain_id).unwrap();
+        assert_eq!(tx.signatures_missing(), 1);
+        let mut block = chain.make_block(Address::from_public_key(&[8; 32]), &genesis, &MemoryBlockStore::new()).unwrap();
+        assert_eq!(block.add_transaction(tx.clone()), Err(ValidationError::NotEnoughSignatures { required: 2, valid: 1 }));
+        //the same key twice is still one signature
+        tx.cosign(&keys[0], &chain_id).unwrap();
+        assert_eq!(tx.signatures_missing(), 1);
+        tx.cosign(&keys[2], &chain_id).unwrap();
+        block.add_transaction(tx).unwrap();
+        assert_eq!(block.balance_of(&policy.address()), 89);
+    }
+}
@@ -0,0 +1,499 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::{Address, Block, Client, DecodeError, Hash, Miner, Transaction, ValidationError};
+
+pub const MAX_PEERS:usize = 8;
+/** Longest line a peer may send, newline included. Comfortably fits a full block. */
+pub const MAX_MESSAGE_LEN:usize = 8 * 1024 * 1024;
+/** How long a write to a peer may block before the peer is dropped. */
+pub const WRITE_TIMEOUT:Duration = Duration::from_secs(10);
+/** How many block and transaction ids a node remembers having relayed. */
+pub const MAX_SEEN:usize = 100_000;
+
+/** Everything nodes say to each other. Sent as one JSON object per line, with blocks and
+    transactions inside it as the hex of their canonical binary encoding.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Message {
+    Hello { listen_addr:String, genesis_id:Hash },
+    GetPeers,
+    Peers(Vec<String>),
+    NewTransaction(#[serde(with = "wire_hex")] Transaction),
+    NewBlock(#[serde(with = "wire_hex")] Block),
+    GetBlock(Hash),
+    BlockResponse(#[serde(with = "wire_hex")] Block)
+}
+
+/** What can go over the wire in its binary encoding. */
+trait WireFormat: Sized {
+    fn to_wire(&self) -> Vec<u8>;
+    fn from_wire(bytes:&[u8]) -> Result<Self, DecodeError>;
+}
+
+impl WireFormat for Transaction {
+    fn to_wire(&self) -> Vec<u8> {
+        self.to_bytes()
+    }
+
+    fn from_wire(bytes:&[u8]) -> Result<Self, DecodeError> {
+        Transaction::from_bytes(bytes)
+    }
+}
+
+impl WireFormat for Block {
+    fn to_wire(&self) -> Vec<u8> {
+        self.to_bytes()
+    }
+
+    fn from_wire(bytes:&[u8]) -> Result<Self, DecodeError> {
+        Block::from_bytes(bytes)
+    }
+}
+
+mod wire_hex {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use super::WireFormat;
+
+    pub fn serialize<T:WireFormat, S:Serializer>(value:&T, serializer:S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(value.to_wire()))
+    }
+
+    pub fn deserialize<'de, T:WireFormat, D:Deserializer<'de>>(deserializer:D) -> Result<T, D::Error> {
+        let bytes = hex::decode(String::deserialize(deserializer)?).map_err(de::Error::custom)?;
+        T::from_wire(&bytes).map_err(de::Error::custom)
+    }
+}
+
+/** What a `Node` needs from the Client or Miner it wraps. */
+pub trait Participant: Send + 'static {
+    fn client(&self) -> &Client;
+    fn receive_transaction(&mut self, tx:Transaction) -> Result<(), ValidationError>;
+    fn receive_block(&mut self, block:Block) -> Result<Block, ValidationError>;
+    fn post_transaction(&mut self, outputs:Vec<(Address, u128)>, custom_fee:Option<u32>) -> Option<Transaction>;
+}
+
+impl Participant for Client {
+    fn client(&self) -> &Client {
+        self
+    }
+
+    fn receive_transaction(&mut self, tx:Transaction) -> Result<(), ValidationError> {
+        Client::receive_transaction(self, tx)
+    }
+
+    fn receive_block(&mut self, block:Block) -> Result<Block, ValidationError> {
+        Client::receive_block(self, block)
+    }
+
+    fn post_transaction(&mut self, outputs:Vec<(Address, u128)>, custom_fee:Option<u32>) -> Option<Transaction> {
+        Client::post_transaction(self, outputs, custom_fee)
+    }
+}
+
+impl Participant for Miner {
+    fn client(&self) -> &Client {
+        &self.client
+    }
+
+    fn receive_transaction(&mut self, tx:Transaction) -> Result<(), ValidationError> {
+        Miner::receive_transaction(self, tx)
+    }
+
+    fn receive_block(&mut self, block:Block) -> Result<Block, ValidationError> {
+        Miner::receive_block(self, block)
+    }
+
+    fn post_transaction(&mut self, outputs:Vec<(Address, u128)>, custom_fee:Option<u32>) -> Option<Transaction> {
+        Miner::post_transaction(self, outputs, custom_fee)
+    }
+}
+
+/** A TCP node wrapping a Client or Miner. Cloning gives another handle to the same node. */
+pub struct Node<P: Participant> {
+    participant: Arc<Mutex<P>>,
+    listen_addr: String,
+    genesis_id: Hash,
+    peers: Arc<Mutex<BTreeMap<String, PeerWriter>>>,
+    seen: Arc<Mutex<Seen>>
+}
+
+impl<P: Participant> Clone for Node<P> {
+    fn clone(&self) -> Self {
+        Node {
+            participant: self.participant.clone(),
+            listen_addr: self.listen_addr.clone(),
+            genesis_id: self.genesis_id.clone(),
+            peers: self.peers.clone(),
+            seen: self.seen.clone()
+        }
+    }
+}
+
+/** The one handle everything writes to a peer through, so messages from different threads can't interleave. */
+type PeerWriter = Arc<Mutex<TcpStream>>;
+
+/** Ids already relayed, forgetting the oldest past `MAX_SEEN`. A forgotten block that comes
+    round again is only a duplicate to the client, so this just saves work.
+ */
+#[derive(Default)]
+struct Seen {
+    ids:BTreeSet<Hash>,
+    order:VecDeque<Hash>
+}
+
+impl Seen {
+    fn contains(&self, id:&Hash) -> bool {
+        self.ids.contains(id)
+    }
+
+    fn insert(&mut self, id:Hash) {
+        if !self.ids.insert(id.clone()) { return }
+        self.order.push_back(id);
+        if self.order.len() > MAX_SEEN {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+}
+
+fn send(writer:&Mutex<TcpStream>, msg:&Message) -> io::Result<()> {
+    let mut line = serde_json::to_string(msg)?;
+    line.push('\n');
+    writer.lock().unwrap().write_all(line.as_bytes())
+}
+
+// reads one line into `line` without the newline, refusing lines longer than MAX_MESSAGE_LEN.
+// Ok(false) once the peer hangs up
+fn read_line<R: BufRead>(reader:&mut R, line:&mut Vec<u8>) -> io::Result<bool> {
+    line.clear();
+    reader.take(MAX_MESSAGE_LEN as u64).read_until(b'\n', line)?;
+    match line.last() {
+        Some(b'\n') => {
+            line.pop();
+            Ok(true)
+        }
+        None => Ok(false),
+        Some(_) if line.len() >= MAX_MESSAGE_LEN => Err(io::Error::new(io::ErrorKind::InvalidData, "message too long")),
+        Some(_) => Ok(false)
+    }
+}
+
+impl<P: Participant> Node<P> {
+    /** Binds `listen_addr` and starts accepting peers in the background.
+        The participant must already have a genesis block.
+     */
+    pub fn start(participant:P, listen_addr:&str) -> io::Result<Self> {
+        let genesis_id = match participant.client().genesis_block() {
+            Some(genesis) => genesis.id(),
+            None => panic!("Trying to start a node without a genesis block.")
+        };
+        let listener = TcpListener::bind(listen_addr)?;
+        let node = Node {
+            participant: Arc::new(Mutex::new(participant)),
+            listen_addr: listener.local_addr()?.to_string(),
+            genesis_id,
+            peers: Arc::new(Mutex::new(BTreeMap::new())),
+            seen: Arc::new(Mutex::new(Seen::default()))
+        };
+        let acceptor = node.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let handler = acceptor.clone();
+                thread::spawn(move || handler.handle_connection(stream, false));
+            }
+        });
+        Ok(node)
+    }
+
+    pub fn listen_addr(&self) -> &str {
+        &self.listen_addr
+    }
+
+    pub fn participant(&self) -> MutexGuard<'_, P> {
+        self.participant.lock().unwrap()
+    }
+
+    pub fn peers(&self) -> Vec<String> {
+        self.peers.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn connect(&self, addr:&str) -> io::Result<()> {
+        if addr == self.listen_addr || self.peers.lock().unwrap().contains_key(addr) {
+            return Ok(());
+        }
+        let stream = TcpStream::connect(addr)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        send(&Mutex::new(stream.try_clone()?), &self.hello())?;
+        let handler = self.clone();
+        thread::spawn(move || handler.handle_connection(stream, true));
+        Ok(())
+    }
+
+    pub fn post_transaction(&self, outputs:Vec<(Address, u128)>, custom_fee:Option<u32>) -> Option<Transaction> {
+        let tx = self.participant().post_transaction(outputs, custom_fee)?;
+        self.seen.lock().unwrap().insert(tx.id());
+        self.broadcast(&Message::NewTransaction(tx.clone()), None);
+        Some(tx)
+    }
+
+    /** Accepts a transaction signed elsewhere, such as one from `Client::import_signed_transaction`, and sends it to every peer. */
+    pub fn broadcast_transaction(&self, tx:Transaction) -> Result<(), ValidationError> {
+        self.participant().receive_transaction(tx.clone())?;
+        self.seen.lock().unwrap().insert(tx.id());
+        self.broadcast(&Message::NewTransaction(tx), None);
+        Ok(())
+    }
+
+    pub fn announce_block(&self, block:Block) {
+        self.seen.lock().unwrap().insert(block.id());
+        self.broadcast(&Message::NewBlock(block), None);
+    }
+
+    pub fn broadcast(&self, msg:&Message, except:Option<&str>) {
+        //sending can block for up to WRITE_TIMEOUT per peer, so not while holding the peer list
+        let peers:Vec<(String, PeerWriter)> = self.peers.lock().unwrap().iter()
+            .filter(|(addr, _)| Some(addr.as_str()) != except)
+            .map(|(addr, writer)| (addr.clone(), writer.clone()))
+            .collect();
+        let dead:Vec<(String, PeerWriter)> = peers.into_iter()
+            .filter(|(_, writer)| send(writer, msg).is_err())
+            .collect();
+        let mut peers = self.peers.lock().unwrap();
+        for (addr, writer) in dead {
+            //unless the peer has reconnected in the meantime
+            if peers.get(&addr).is_some_and(|current| Arc::ptr_eq(current, &writer)) {
+                peers.remove(&addr);
+            }
+        }
+    }
+
+    fn hello(&self) -> Message {
+        Message::Hello { listen_addr: self.listen_addr.clone(), genesis_id: self.genesis_id.clone() }
+    }
+
+    fn log(&self, msg:&str) {
+        self.participant().client().log(msg);
+    }
+
+    fn handle_connection(&self, stream:TcpStream, dialed:bool) {
+        if stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_err() { return }
+        let writer:PeerWriter = match stream.try_clone() {
+            Ok(writer) => Arc::new(Mutex::new(writer)),
+            Err(_) => return
+        };
+        let mut peer_addr:Option<String> = None;
+        let mut reader = BufReader::new(stream);
+        let mut line = vec![];
+        loop {
+            match read_line(&mut reader, &mut line) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) => {
+                    self.log(&format!("Dropping peer: {}", err));
+                    break;
+                }
+            }
+            let msg:Message = match serde_json::from_slice(&line) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    self.log(&format!("Dropping malformed message: {}", err));
+                    continue;
+                }
+            };
+            let keep_going = match (&peer_addr, msg) {
+                (None, Message::Hello { listen_addr, genesis_id }) => {
+                    match self.handshake(&writer, dialed, &listen_addr, &genesis_id) {
+                        Ok(()) => {
+                            peer_addr = Some(listen_addr);
+                            true
+                        }
+                        Err(_) => false
+                    }
+                }
+                (None, _) => false,
+                (Some(addr), msg) => self.handle_message(&writer, addr, msg).is_ok()
+            };
+            if !keep_going { break }
+        }
+        if let Some(addr) = peer_addr {
+            self.peers.lock().unwrap().remove(&addr);
+        }
+    }
+
+    fn handshake(&self, writer:&PeerWriter, dialed:bool, peer_addr:&str, genesis_id:&Hash) -> io::Result<()> {
+        let refuse = |reason:&str| io::Error::new(io::ErrorKind::ConnectionRefused, reason.to_string());
+        if *genesis_id != self.genesis_id {
+            self.log(&format!("Refusing peer {}: different genesis block", peer_addr));
+            return Err(refuse("different genesis block"));
+        }
+        {
+            let mut peers = self.peers.lock().unwrap();
+            if peer_addr == self.listen_addr || peers.contains_key(peer_addr) || peers.len() >= MAX_PEERS {
+                return Err(refuse("duplicate peer or peer list full"));
+            }
+            peers.insert(peer_addr.to_string(), writer.clone());
+        }
+        if !dialed {
+            send(writer, &self.hello())?;
+        }
+        send(writer, &Message::GetPeers)?;
+        let tip = self.participant().client().last_block();
+        if let Some(tip) = tip {
+            send(writer, &Message::NewBlock(tip))?;
+        }
+        Ok(())
+    }
+
+    fn handle_message(&self, writer:&PeerWriter, peer_addr:&str, msg:Message) -> io::Result<()> {
+        match msg {
+            Message::Hello { .. } => Ok(()),
+            Message::GetPeers => {
+                let mut peers = self.peers();
+                peers.retain(|addr| addr != peer_addr);
+                send(writer, &Message::Peers(peers))
+            }
+            Message::Peers(addrs) => {
+                for addr in addrs {
+                    if self.peers.lock().unwrap().len() >= MAX_PEERS { break }
+                    if let Err(err) = self.connect(&addr) {
+                        self.log(&format!("Could not connect to {}: {}", addr, err));
+                    }
+                }
+                Ok(())
+            }
+            Message::NewTransaction(tx) => {
+                //only marked seen once accepted, so a bad copy can't shadow the real one
+                if self.seen.lock().unwrap().contains(&tx.id()) { return Ok(()) }
+                let result = self.participant().receive_transaction(tx.clone());
+                match result {
+                    Ok(()) => {
+                        self.seen.lock().unwrap().insert(tx.id());
+                        self.broadcast(&Message::NewTransaction(tx), Some(peer_addr));
+                    }
+                    Err(err) => self.log(&format!("Rejected tx from {}: {}", peer_addr, err))
+                }
+                Ok(())
+            }
+            Message::NewBlock(block) => self.handle_block(writer, peer_addr, block, true),
+            Message::BlockResponse(block) => self.handle_block(writer, peer_addr, block, false),
+            Message::GetBlock(id) => {
+                let block = self.participant().client().blocks.get(&id);
+                match block {
+                    Some(block) => send(writer, &Message::BlockResponse(block)),
+                    None => Ok(())
+                }
+            }
+        }
+    }
+
+    fn handle_block(&self, writer:&PeerWriter, peer_addr:&str, block:Block, relay:bool) -> io::Result<()> {
+        //only marked seen once accepted, so a bad copy can't shadow the real one
+        if self.seen.lock().unwrap().contains(&block.id()) { return Ok(()) }
+        let result = self.participant().receive_block(block.clone());
+        match result {
+            Ok(_) => {
+                self.seen.lock().unwrap().insert(block.id());
+                if relay {
+                    self.broadcast(&Message::NewBlock(block), Some(peer_addr));
+                }
+                Ok(())
+            }
+            Err(ValidationError::UnknownParent(parent)) => send(writer, &Message::GetBlock(parent)),
+            Err(ValidationError::DuplicateBlock(_)) => Ok(()),
+            Err(err) => {
+                self.log(&format!("Rejected block from {}: {}", peer_addr, err));
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Node<Miner> {
+    /** Mines until this node has produced `count` blocks, announcing each one to the peers.
+        The miner is only locked for one batch at a time, so peers' messages are handled in between.
+     */
+    pub fn mine_blocks(&self, count:usize) {
+        let mut found = 0;
+        while found < count {
+            let block = self.participant().find_proof();
+            if let Some(block) = block {
+                self.announce_block(block);
+                found += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::{Message, Node, Participant};
+    use crate::{Address, Blockchain, Client, Hash, Miner};
+
+    // polls until `done`, failing the test after a few seconds
+    fn wait_for(what:&str, done:impl Fn() -> bool) {
+        let started = Instant::now();
+        while !done() {
+            assert!(started.elapsed() < Duration::from_secs(10), "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    fn tip<P:Participant>(node:&Node<P>) -> (Hash, u32) {
+        let tip = node.participant().client().last_block().unwrap();
+        (tip.id(), tip.header.chain_length)
+    }
+
+    #[test]
+    fn blocks_travel_in_their_binary_encoding() {
+        let genesis = Blockchain::new().make_genesis(BTreeMap::from([(Address::from_public_key(&[1; 32]), 10)]));
+        let line = serde_json::to_string(&Message::NewBlock(genesis.clone())).unwrap();
+        assert_eq!(line, format!("{{\"NewBlock\":\"{}\"}}", hex::encode(genesis.to_bytes())));
+        match serde_json::from_str(&line).unwrap() {
+            Message::NewBlock(block) => assert_eq!(block.to_bytes(), genesis.to_bytes()),
+            other => panic!("decoded as {:?}", other)
+        }
+    }
+
+    #[test]
+    fn localhost_nodes_converge() {
+        let chain = Blockchain::new().with_pow_leading_zeros(0).with_retarget_interval(0);
+        let payer = Client::new("payer".to_string(), chain.clone(), None, None);
+        let genesis = chain.make_genesis(BTreeMap::from([(payer.address(), 50)]));
+        let mut payer = payer;
+        payer.set_genesis(genesis.clone());
+        let mut first = Miner::new("first".to_string(), chain.clone(), Some(genesis.clone()), None, None);
+        let mut second = Miner::new("second".to_string(), chain.clone(), Some(genesis), None, None);
+        first.initialize();
+        second.initialize();
+        let payee = second.address();
+        let payer = Node::start(payer, "127.0.0.1:0").unwrap();
+        let first = Node::start(first, "127.0.0.1:0").unwrap();
+        let second = Node::start(second, "127.0.0.1:0").unwrap();
+        //the second miner only knows the first, and learns of the payer from it
+        first.connect(payer.listen_addr()).unwrap();
+        second.connect(first.listen_addr()).unwrap();
+        wait_for("peer discovery", || payer.peers().len() == 2 && second.peers().len() == 2);
+        let converged = || tip(&payer) == tip(&first) && tip(&first) == tip(&second);
+
+        first.mine_blocks(3);
+        wait_for("the first miner's blocks", converged);
+        payer.post_transaction(vec![(payee.clone(), 7)], None).unwrap();
+        wait_for("the payment to reach the second miner", || second.participant().mempool().len() == 1);
+        second.mine_blocks(3);
+        wait_for("the second miner's blocks", converged);
+        first.mine_blocks(2);
+        wait_for("the first miner's last blocks", converged);
+
+        assert_eq!(tip(&second).1, 8);
+        assert_eq!(payer.participant().client().last_block().unwrap().balance_of(&payee), 7);
+    }
+}
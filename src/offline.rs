@@ -0,0 +1,108 @@
+use std::fmt::Write;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{de, Deserialize, Serialize};
+use crate::{calc_address, Hash, Transaction, ValidationError};
+
+/** Version of the signing request format. Bumped whenever its fields change. */
+pub const SIGNING_REQUEST_VERSION:u32 = 1;
+
+/** A transaction on its way to an offline machine to be signed, and back.
+    It carries the chain id because that is part of what gets signed, so the signing
+    machine never needs to see the chain. Passed around as JSON.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SigningRequest {
+    pub version:u32,
+    pub chain_id:Hash,
+    pub transaction:Transaction
+}
+
+impl SigningRequest {
+    pub fn new(transaction:Transaction, chain_id:Hash) -> Self {
+        SigningRequest { version: SIGNING_REQUEST_VERSION, chain_id, transaction }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /** Inverse of `to_json`. Also rejects a request whose key or policy doesn't match its sender,
+        or whose addresses are written for different networks, so that `summary` can be trusted.
+     */
+    pub fn from_json(json:&str) -> serde_json::Result<Self> {
+        let request:SigningRequest = serde_json::from_str(json)?;
+        if request.version != SIGNING_REQUEST_VERSION {
+            return Err(de::Error::custom(format!("unsupported signing request version {}", request.version)));
+        }
+        let tx = &request.transaction;
+        tx.check_shape().map_err(de::Error::custom)?;
+        tx.check_sender().map_err(de::Error::custom)?;
+        tx.check_outputs().map_err(de::Error::custom)?;
+        tx.total_output().map_err(de::Error::custom)?;
+        if let Some((address, _)) = tx.outputs.iter().find(|(address, _)| address.network() != tx.from.network()) {
+            return Err(de::Error::custom(format!("pays {} on another network than {}", address, tx.from)));
+        }
+        Ok(request)
+    }
+
+    /** What the transaction does, for whoever is about to sign it. */
+    pub fn summary(&self) -> String {
+        let tx = &self.transaction;
+        let mut summary = String::new();
+        writeln!(summary, "Transaction {}", tx.id().as_hex()).unwrap();
+        writeln!(summary, "Chain       {}", self.chain_id.as_hex()).unwrap();
+        match &tx.multisig {
+            Some(policy) => writeln!(summary, "From        {} ({} of {} multisig)", tx.from, policy.threshold(), policy.keys().len()),
+            None => writeln!(summary, "From        {}", tx.from)
+        }.unwrap();
+        writeln!(summary, "Nonce       {}", tx.nonce).unwrap();
+        for (address, amount) in &tx.outputs {
+            writeln!(summary, "Pay         {} to {}", amount, address).unwrap();
+        }
+        writeln!(summary, "Fee         {}", tx.fee).unwrap();
+        match tx.total_output() {
+            Ok(total) => writeln!(summary, "Total       {}", total),
+            Err(_) => writeln!(summary, "Total       overflows")
+        }.unwrap();
+        if !tx.data.is_empty() {
+            writeln!(summary, "Data        {:?}", tx.data).unwrap();
+        }
+        match &tx.multisig {
+            Some(policy) => {
+                let signers:Vec<String> = tx.cosignatures.keys()
+                    .filter_map(|index| policy.keys().get(usize::from(*index)))
+                    .map(|key| calc_address(key).on_network(tx.from.network()).to_string())
+                    .collect();
+                writeln!(summary, "Signatures  {} of {} required", signers.len(), policy.threshold()).unwrap();
+                for signer in signers {
+                    writeln!(summary, "Signed by   {}", signer).unwrap();
+                }
+            }
+            None => writeln!(summary, "Signatures  {}", if tx.sig.is_some() { "signed" } else { "unsigned" }).unwrap()
+        }
+        summary
+    }
+
+    /** Signs with `keypair`, or adds its cosignature to a multisig transaction.
+        Errors if the key isn't the sender's or one of the policy's.
+     */
+    pub fn sign(&mut self, keypair:&Ed25519KeyPair) -> Result<(), ValidationError> {
+        if self.transaction.multisig.is_some() {
+            return self.transaction.cosign(keypair, &self.chain_id);
+        }
+        if keypair.public_key().as_ref() != self.transaction.pubkey_bytes.as_slice() {
+            return Err(ValidationError::AddressMismatch {
+                from: self.transaction.from.clone(),
+                derived: calc_address(keypair.public_key().as_ref())
+            });
+        }
+        self.transaction.sign(keypair, &self.chain_id);
+        Ok(())
+    }
+
+    /** Whether every signature the transaction needs is there and valid. */
+    pub fn is_complete(&self) -> bool {
+        self.transaction.valid_signature(&self.chain_id)
+    }
+}
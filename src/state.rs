@@ -0,0 +1,373 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use crate::{hash, u128_to_bytes, Address, Hash, ValidationError, HASH_LEN};
+
+const LEAF_PREFIX:u8 = 0;
+const BRANCH_PREFIX:u8 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Account {
+    pub balance:u128,
+    /** The nonce this account's next transaction must use. */
+    pub nonce:u128
+}
+
+fn empty_hash() -> Hash {
+    Hash(vec![0; HASH_LEN])
+}
+
+/** Where `address` lives in the tree. */
+pub fn account_key(address:&Address) -> Hash {
+    Hash(hash(&address.to_bytes()))
+}
+
+fn leaf_hash(key:&Hash, account:&Account) -> Hash {
+    let mut bytes = Vec::with_capacity(1 + HASH_LEN + 32);
+    bytes.push(LEAF_PREFIX);
+    bytes.extend_from_slice(key);
+    bytes.extend_from_slice(&u128_to_bytes(&account.balance));
+    bytes.extend_from_slice(&u128_to_bytes(&account.nonce));
+    Hash(hash(&bytes))
+}
+
+fn branch_hash(left:&Hash, right:&Hash) -> Hash {
+    let mut bytes = Vec::with_capacity(1 + 2 * HASH_LEN);
+    bytes.push(BRANCH_PREFIX);
+    bytes.extend_from_slice(left);
+    bytes.extend_from_slice(right);
+    Hash(hash(&bytes))
+}
+
+// bit `depth` of `key`, most significant first. true means the right subtree.
+fn bit(key:&Hash, depth:usize) -> bool {
+    key[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+/** A subtree holding a single account is stored as just that leaf, wherever it sits,
+    so the tree is only as deep as it takes to tell the keys apart.
+ */
+enum Node {
+    Leaf { key:Hash, address:Address, account:Account, hash:Hash },
+    Branch { left:Option<Arc<Node>>, right:Option<Arc<Node>>, hash:Hash }
+}
+
+impl Node {
+    fn hash(&self) -> &Hash {
+        match self {
+            Node::Leaf { hash, .. } | Node::Branch { hash, .. } => hash
+        }
+    }
+
+    fn leaf(key:Hash, address:Address, account:Account) -> Arc<Node> {
+        let hash = leaf_hash(&key, &account);
+        Arc::new(Node::Leaf { key, address, account, hash })
+    }
+
+    fn branch(left:Option<Arc<Node>>, right:Option<Arc<Node>>) -> Arc<Node> {
+        let hash = branch_hash(
+            &left.as_ref().map_or_else(empty_hash, |node| node.hash().clone()),
+            &right.as_ref().map_or_else(empty_hash, |node| node.hash().clone())
+        );
+        Arc::new(Node::Branch { left, right, hash })
+    }
+}
+
+// returns the new subtree at `depth` with `leaf` inserted, sharing every untouched node with `node`
+fn insert(node:Option<&Arc<Node>>, depth:usize, leaf:Arc<Node>) -> Arc<Node> {
+    let key = match leaf.as_ref() {
+        Node::Leaf { key, .. } => key.clone(),
+        Node::Branch { .. } => unreachable!()
+    };
+    match node.map(|node| node.as_ref()) {
+        None => leaf,
+        Some(Node::Leaf { key: existing, .. }) if *existing == key => leaf,
+        Some(Node::Leaf { key: existing, .. }) => {
+            //push the existing leaf down until the two keys part ways
+            let existing_right = bit(existing, depth);
+            let existing = node.cloned();
+            if existing_right == bit(&key, depth) {
+                let child = insert(existing.as_ref(), depth + 1, leaf);
+                if existing_right { Node::branch(None, Some(child)) } else { Node::branch(Some(child), None) }
+            }
+            else if existing_right { Node::branch(Some(leaf), existing) }
+            else { Node::branch(existing, Some(leaf)) }
+        }
+        Some(Node::Branch { left, right, .. }) => {
+            if bit(&key, depth) {
+                Node::branch(left.clone(), Some(insert(right.as_ref(), depth + 1, leaf)))
+            }
+            else {
+                Node::branch(Some(insert(left.as_ref(), depth + 1, leaf)), right.clone())
+            }
+        }
+    }
+}
+
+/** Sibling hashes from the root down to where `key`'s path ends, plus the key and account of the leaf
+    found there, if any. For an account that doesn't exist the path ends in an empty subtree or in some
+    other account's leaf. The leaf is hashed again when checked, so nothing else can stand in for it.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AccountProof {
+    pub siblings:Vec<Hash>,
+    pub leaf:Option<(Hash, Account)>
+}
+
+/** Checks that `address` holds `account` (or has no account at all, for None) under `state_root`. */
+pub fn verify_account_proof(state_root:&Hash, address:&Address, account:Option<&Account>, proof:&AccountProof) -> bool {
+    let key = account_key(address);
+    if proof.siblings.len() > HASH_LEN * 8 {
+        return false;
+    }
+    let mut node = match (account, &proof.leaf) {
+        (Some(account), Some((leaf_key, leaf_account))) => {
+            if *leaf_key != key || leaf_account != account { return false }
+            leaf_hash(&key, account)
+        }
+        (Some(_), None) => return false,
+        (None, None) => empty_hash(),
+        (None, Some((leaf_key, leaf_account))) => {
+            //someone else's leaf sitting where `address` would be
+            if *leaf_key == key || (0..proof.siblings.len()).any(|depth| bit(leaf_key, depth) != bit(&key, depth)) {
+                return false;
+            }
+            leaf_hash(leaf_key, leaf_account)
+        }
+    };
+    for (depth, sibling) in proof.siblings.iter().enumerate().rev() {
+        node = if bit(&key, depth) { branch_hash(sibling, &node) } else { branch_hash(&node, sibling) };
+    }
+    node == *state_root
+}
+
+/** Every account as of some block, as a sparse Merkle tree keyed by `account_key`.
+    Nodes are shared between versions, so a block's state only costs the paths its
+    transactions touched. Only the root goes in the block header.
+ */
+#[derive(Clone)]
+pub struct AccountState {
+    root:Option<Arc<Node>>,
+    len:usize,
+    total_balance:u128,
+    /** Coinbase payouts that can't be spent yet, keyed by the height of the block that minted them.
+        They follow from the chain's coinbases, so they aren't part of the root.
+     */
+    pub immature:BTreeMap<u32, (Address, u128)>,
+    /** Id of the genesis block this state descends from. */
+    pub chain_id:Hash
+}
+
+impl Default for AccountState {
+    fn default() -> Self {
+        AccountState { root: None, len: 0, total_balance: 0, immature: BTreeMap::new(), chain_id: empty_hash() }
+    }
+}
+
+impl fmt::Debug for AccountState {
+    fn fmt(&self, f:&mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccountState")
+            .field("root", &self.root().as_hex())
+            .field("accounts", &self.len)
+            .field("immature", &self.immature)
+            .field("chain_id", &self.chain_id.as_hex())
+            .finish()
+    }
+}
+
+impl AccountState {
+    pub fn new() -> Self {
+        AccountState::default()
+    }
+
+    /** Errors if the balances add up to more than a u128 holds. */
+    pub fn from_balances(balances:&BTreeMap<Address, u128>) -> Result<Self, ValidationError> {
+        let mut state = AccountState::new();
+        for (address, balance) in balances {
+            state.set_balance(address, *balance)?;
+        }
+        Ok(state)
+    }
+
+    pub fn root(&self) -> Hash {
+        self.root.as_ref().map_or_else(empty_hash, |node| node.hash().clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, address:&Address) -> Option<Account> {
+        let key = account_key(address);
+        let mut node = self.root.as_ref();
+        let mut depth = 0;
+        while let Some(current) = node {
+            match current.as_ref() {
+                Node::Leaf { key: leaf_key, account, .. } => {
+                    return if *leaf_key == key { Some(*account) } else { None };
+                }
+                Node::Branch { left, right, .. } => {
+                    node = if bit(&key, depth) { right.as_ref() } else { left.as_ref() };
+                    depth += 1;
+                }
+            }
+        }
+        None
+    }
+
+    pub fn balance_of(&self, address:&Address) -> u128 {
+        self.get(address).map_or(0, |account| account.balance)
+    }
+
+    pub fn nonce_of(&self, address:&Address) -> u128 {
+        self.get(address).map_or(0, |account| account.nonce)
+    }
+
+    /** Errors, leaving the state as it was, if every balance together would no longer fit in a u128. */
+    pub fn set(&mut self, address:&Address, account:Account) -> Result<(), ValidationError> {
+        let old = self.get(address);
+        //the old balance is part of the total, so taking it out can't underflow
+        let total_balance = (self.total_balance - old.map_or(0, |old| old.balance)).checked_add(account.balance)
+            .ok_or_else(|| ValidationError::BalanceOverflow(address.clone()))?;
+        if old.is_none() {
+            self.len += 1;
+        }
+        self.total_balance = total_balance;
+        let leaf = Node::leaf(account_key(address), address.clone(), account);
+        self.root = Some(insert(self.root.as_ref(), 0, leaf));
+        Ok(())
+    }
+
+    pub fn set_balance(&mut self, address:&Address, balance:u128) -> Result<(), ValidationError> {
+        let account = self.get(address).unwrap_or_default();
+        self.set(address, Account { balance, ..account })
+    }
+
+    pub fn set_nonce(&mut self, address:&Address, nonce:u128) -> Result<(), ValidationError> {
+        let account = self.get(address).unwrap_or_default();
+        self.set(address, Account { nonce, ..account })
+    }
+
+    /** Sum of every spendable balance. */
+    pub fn total_balance(&self) -> u128 {
+        self.total_balance
+    }
+
+    /** Every account, in key order. */
+    pub fn accounts(&self) -> Vec<(Address, Account)> {
+        let mut accounts = vec![];
+        let mut stack:Vec<&Arc<Node>> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            match node.as_ref() {
+                Node::Leaf { address, account, .. } => accounts.push((address.clone(), *account)),
+                Node::Branch { left, right, .. } => {
+                    stack.extend(right.iter());
+                    stack.extend(left.iter());
+                }
+            }
+        }
+        accounts
+    }
+
+    /** Proof of `address`'s account, or of its absence. Check it with `verify_account_proof`. */
+    pub fn prove(&self, address:&Address) -> AccountProof {
+        let key = account_key(address);
+        let mut siblings = vec![];
+        let mut node = self.root.as_ref();
+        let mut depth = 0;
+        while let Some(current) = node {
+            match current.as_ref() {
+                Node::Leaf { key: leaf_key, account, .. } => {
+                    return AccountProof { siblings, leaf: Some((leaf_key.clone(), *account)) };
+                }
+                Node::Branch { left, right, .. } => {
+                    let (next, sibling) = if bit(&key, depth) { (right, left) } else { (left, right) };
+                    siblings.push(sibling.as_ref().map_or_else(empty_hash, |node| node.hash().clone()));
+                    node = next.as_ref();
+                    depth += 1;
+                }
+            }
+        }
+        AccountProof { siblings, leaf: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{calc_address, hash, Address, Hash, ValidationError, HASH_LEN};
+    use super::{account_key, bit, verify_account_proof, Account, AccountProof, AccountState};
+
+    fn address(name:&str) -> Address {
+        calc_address(&hash(name.as_bytes()))
+    }
+
+    fn state() -> AccountState {
+        let mut state = AccountState::new();
+        for i in 0..50 {
+            state.set(&address(&format!("account{}", i)), Account { balance: 100 + i, nonce: i }).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn proves_membership() {
+        let state = state();
+        let holder = address("account7");
+        let proof = state.prove(&holder);
+        assert!(verify_account_proof(&state.root(), &holder, Some(&Account { balance: 107, nonce: 7 }), &proof));
+        assert!(!verify_account_proof(&state.root(), &holder, Some(&Account { balance: 108, nonce: 7 }), &proof));
+        assert!(!verify_account_proof(&state.root(), &holder, None, &proof));
+    }
+
+    #[test]
+    fn proves_absence() {
+        let state = state();
+        for i in 0..50 {
+            let nobody = address(&format!("nobody{}", i));
+            let proof = state.prove(&nobody);
+            assert!(verify_account_proof(&state.root(), &nobody, None, &proof));
+            assert!(!verify_account_proof(&state.root(), &nobody, Some(&Account::default()), &proof));
+        }
+        let empty = AccountState::new();
+        assert!(verify_account_proof(&empty.root(), &address("x"), None, &empty.prove(&address("x"))));
+    }
+
+    #[test]
+    fn rejects_forged_absence() {
+        let state = state();
+        let holder = address("account7");
+        let key = account_key(&holder);
+        let proof = state.prove(&holder);
+        //stop one level early and claim a made up leaf on the same path sits there
+        let depth = proof.siblings.len() - 1;
+        let mut fake_key = key.to_vec();
+        fake_key[HASH_LEN - 1] ^= 1;
+        let fake_key = Hash(fake_key);
+        assert!((0..depth).all(|i| bit(&fake_key, i) == bit(&key, i)));
+        for account in [Account::default(), Account { balance: 107, nonce: 7 }] {
+            let forged = AccountProof { siblings: proof.siblings[..depth].to_vec(), leaf: Some((fake_key.clone(), account)) };
+            assert!(!verify_account_proof(&state.root(), &holder, None, &forged));
+        }
+        //nor can an empty subtree or another account's proof stand in
+        let forged = AccountProof { siblings: proof.siblings[..depth].to_vec(), leaf: None };
+        assert!(!verify_account_proof(&state.root(), &holder, None, &forged));
+        assert!(!verify_account_proof(&state.root(), &holder, None, &state.prove(&address("account8"))));
+    }
+
+    #[test]
+    fn refuses_to_overflow_the_total() {
+        let mut state = state();
+        let root = state.root();
+        let total = state.total_balance();
+        let rich = address("rich");
+        assert_eq!(state.set_balance(&rich, u128::MAX - total + 1), Err(ValidationError::BalanceOverflow(rich.clone())));
+        assert_eq!((state.root(), state.total_balance(), state.len()), (root, total, 50));
+        state.set_balance(&rich, u128::MAX - total).unwrap();
+        assert_eq!(state.total_balance(), u128::MAX);
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::{block_work, u128_to_bytes, u64_to_bytes, AccountState, Block, Hash, HASH_LEN};
+
+const BLOCKS_FILE:&str = "blocks.dat";
+const INDEX_FILE:&str = "index.dat";
+// hash, offset into blocks.dat, cumulative work
+const INDEX_RECORD_LEN:usize = HASH_LEN + 8 + 16;
+
+/** Where a Client keeps the blocks it has accepted. */
+pub trait BlockStore: Send {
+    fn get(&self, id:&Hash) -> Option<Block>;
+    fn contains(&self, id:&Hash) -> bool;
+    /** Stores `block` along with the total work of the chain ending in it. On error nothing is stored. */
+    fn insert(&mut self, block:Block, total_work:u128) -> io::Result<()>;
+    fn total_work(&self, id:&Hash) -> Option<u128>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /** The block with the most cumulative work. Ties go to the lowest hash. */
+    fn tip(&self) -> Option<Hash>;
+}
+
+fn better_tip(tip:&Option<(u128, Hash)>, total_work:u128, id:&Hash) -> bool {
+    match tip {
+        Some((tip_work, tip_id)) => total_work > *tip_work || (total_work == *tip_work && id < tip_id),
+        None => true
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryBlockStore {
+    blocks:BTreeMap<Hash, (Block, u128)>,
+    tip:Option<(u128, Hash)>
+}
+
+impl MemoryBlockStore {
+    pub fn new() -> Self {
+        MemoryBlockStore::default()
+    }
+}
+
+impl BlockStore for MemoryBlockStore {
+    fn get(&self, id:&Hash) -> Option<Block> {
+        self.blocks.get(id).map(|(block, _)| block.clone())
+    }
+
+    fn contains(&self, id:&Hash) -> bool {
+        self.blocks.contains_key(id)
+    }
+
+    fn insert(&mut self, block:Block, total_work:u128) -> io::Result<()> {
+        let id = block.id();
+        if self.blocks.contains_key(&id) { return Ok(()) }
+        if better_tip(&self.tip, total_work, &id) {
+            self.tip = Some((total_work, id.clone()));
+        }
+        self.blocks.insert(id, (block, total_work));
+        Ok(())
+    }
+
+    fn total_work(&self, id:&Hash) -> Option<u128> {
+        self.blocks.get(id).map(|(_, total_work)| *total_work)
+    }
+
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn tip(&self) -> Option<Hash> {
+        self.tip.as_ref().map(|(_, id)| id.clone())
+    }
+}
+
+/** Append-only block log in a data directory.
+    `blocks.dat` holds length-prefixed blocks in their canonical encoding, `index.dat` maps each hash to its offset
+    and cumulative work. Account state isn't written out; `open` replays the log to rebuild it.
+ */
+pub struct FileBlockStore {
+    blocks_path:PathBuf,
+    blocks_file:File,
+    index_file:File,
+    index:BTreeMap<Hash, (u64, u128)>,
+    states:BTreeMap<Hash, AccountState>,
+    end:u64,
+    tip:Option<(u128, Hash)>
+}
+
+impl FileBlockStore {
+    /** Opens or creates the store, recovering any blocks written after the last index entry
+        and cutting off a torn or damaged tail.
+     */
+    pub fn open<P: AsRef<Path>>(data_dir:P) -> io::Result<Self> {
+        fs::create_dir_all(&data_dir)?;
+        let blocks_path = data_dir.as_ref().join(BLOCKS_FILE);
+        let index_path = data_dir.as_ref().join(INDEX_FILE);
+        let blocks_file = OpenOptions::new().read(true).append(true).create(true).open(&blocks_path)?;
+        let mut index_file = OpenOptions::new().read(true).append(true).create(true).open(&index_path)?;
+
+        let mut store = FileBlockStore {
+            blocks_path,
+            blocks_file,
+            index_file: index_file.try_clone()?,
+            index: BTreeMap::new(),
+            states: BTreeMap::new(),
+            end: 0,
+            tip: None
+        };
+
+        let mut index_bytes = vec![];
+        index_file.read_to_end(&mut index_bytes)?;
+        let data_len = store.blocks_file.metadata()?.len();
+        //an indexed block that doesn't read back and replay is dropped along with everything after it,
+        //so a damaged record can never become the tip
+        for record in index_bytes.chunks_exact(INDEX_RECORD_LEN) {
+            let id = Hash(record[..HASH_LEN].to_vec());
+            let offset = u64::from_le_bytes(record[HASH_LEN..HASH_LEN + 8].try_into().unwrap());
+            let total_work = u128::from_le_bytes(record[HASH_LEN + 8..].try_into().unwrap());
+            if offset != store.end { break }
+            match store.replay(offset, data_len) {
+                Some((block, record_end)) if block.id() == id => {
+                    store.end = record_end;
+                    store.track(id.clone(), offset, total_work);
+                    store.states.insert(id, block.state);
+                }
+                _ => break
+            }
+        }
+        //rewrite the index if it had a torn record or entries that didn't check out
+        let indexed_len = (store.index.len() * INDEX_RECORD_LEN) as u64;
+        if indexed_len != index_bytes.len() as u64 {
+            store.index_file.set_len(indexed_len)?;
+        }
+
+        //blocks appended after the last index write. Their parents are always written first.
+        while let Some((block, record_end)) = store.replay(store.end, data_len) {
+            let parent_work = store.total_work(&block.header.prev_block_hash).unwrap_or(0);
+            let total_work = parent_work.saturating_add(block_work(&block.header.pow_target));
+            let offset = store.end;
+            store.end = record_end;
+            store.write_index(&block.id(), offset, total_work)?;
+            store.track(block.id(), offset, total_work);
+            store.states.insert(block.id(), block.state);
+        }
+        if store.end < data_len {
+            store.blocks_file.set_len(store.end)?;
+        }
+        Ok(store)
+    }
+
+    // reads the record at `offset` and replays its block over its parent's state. None if the record runs past
+    // `data_len`, doesn't decode, or doesn't follow from a stored parent. Otherwise the block and where its record ends.
+    fn replay(&self, offset:u64, data_len:u64) -> Option<(Block, u64)> {
+        let len = self.read_len(offset).ok()?;
+        let record_end = offset.checked_add(8)?.checked_add(len)?;
+        if record_end > data_len { return None }
+        let mut block = self.read_at(offset).ok()?;
+        if !block.is_genesis() {
+            let parent = self.get(&block.header.prev_block_hash)?;
+            block.rerun(&parent).ok()?;
+        }
+        Some((block, record_end))
+    }
+
+    fn track(&mut self, id:Hash, offset:u64, total_work:u128) {
+        if better_tip(&self.tip, total_work, &id) {
+            self.tip = Some((total_work, id.clone()));
+        }
+        self.index.insert(id, (offset, total_work));
+    }
+
+    fn read_len(&self, offset:u64) -> io::Result<u64> {
+        let mut file = File::open(&self.blocks_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut len_bytes = [0u8; 8];
+        file.read_exact(&mut len_bytes)?;
+        Ok(u64::from_le_bytes(len_bytes))
+    }
+
+    fn read_at(&self, offset:u64) -> io::Result<Block> {
+        let len = self.read_len(offset)?;
+        let mut file = File::open(&self.blocks_path)?;
+        file.seek(SeekFrom::Start(offset + 8))?;
+        let mut bytes = vec![];
+        file.take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block record"));
+        }
+        Block::from_bytes(&bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn write_index(&mut self, id:&Hash, offset:u64, total_work:u128) -> io::Result<()> {
+        let mut record = Vec::with_capacity(INDEX_RECORD_LEN);
+        record.extend_from_slice(id);
+        record.extend_from_slice(&u64_to_bytes(&offset));
+        record.extend_from_slice(&u128_to_bytes(&total_work));
+        self.index_file.write_all(&record)
+    }
+
+    fn append(&mut self, block:&Block, total_work:u128) -> io::Result<()> {
+        let bytes = block.to_bytes();
+        let offset = self.end;
+        let mut record = Vec::with_capacity(8 + bytes.len());
+        record.extend_from_slice(&u64_to_bytes(&(bytes.len() as u64)));
+        record.extend_from_slice(&bytes);
+        let written = self.blocks_file.write_all(&record)
+            .and_then(|_| self.blocks_file.sync_data())
+            .and_then(|_| self.write_index(&block.id(), offset, total_work));
+        if let Err(err) = written {
+            //cut off whatever made it to disk, so the next block lands where the index expects it
+            let _ = self.blocks_file.set_len(self.end);
+            let _ = self.index_file.set_len((self.index.len() * INDEX_RECORD_LEN) as u64);
+            return Err(err);
+        }
+        self.end += record.len() as u64;
+        self.track(block.id(), offset, total_work);
+        self.states.insert(block.id(), block.state.clone());
+        Ok(())
+    }
+}
+
+impl BlockStore for FileBlockStore {
+    fn get(&self, id:&Hash) -> Option<Block> {
+        let (offset, _) = self.index.get(id)?;
+        let mut block = self.read_at(*offset).ok()?;
+        block.state = self.states.get(id)?.clone();
+        Some(block)
+    }
+
+    fn contains(&self, id:&Hash) -> bool {
+        self.index.contains_key(id)
+    }
+
+    fn insert(&mut self, block:Block, total_work:u128) -> io::Result<()> {
+        if self.contains(&block.id()) { return Ok(()) }
+        self.append(&block, total_work)
+    }
+
+    fn total_work(&self, id:&Hash) -> Option<u128> {
+        self.index.get(id).map(|(_, total_work)| *total_work)
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn tip(&self) -> Option<Hash> {
+        self.tip.as_ref().map(|(_, id)| id.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+    use ring::signature::Ed25519KeyPair;
+    use crate::{calc_address, hash, Block, Blockchain, Client, Miner};
+    use super::{FileBlockStore, BLOCKS_FILE, INDEX_FILE};
+
+    fn data_dir(name:&str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rusted-gold-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn chain() -> Blockchain {
+        Blockchain::new().with_pow_leading_zeros(0).with_retarget_interval(0).with_coinbase_maturity(1)
+    }
+
+    fn keypair() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap()
+    }
+
+    fn reopen(dir:&PathBuf) -> Client {
+        Client::with_store("M".into(), chain(), Box::new(FileBlockStore::open(dir).unwrap()), Some(keypair()))
+    }
+
+    // mines a few blocks with a payment in them into a fresh store, returning the tip
+    fn mine(dir:&PathBuf) -> Block {
+        let client = Client::with_store("M".into(), chain(), Box::new(FileBlockStore::open(dir).unwrap()), Some(keypair()));
+        let mut miner = Miner::from_client(client, None);
+        miner.client.set_genesis(chain().make_genesis(Default::default()));
+        miner.initialize();
+        for _ in 0..4 { miner.mine_block(); }
+        miner.post_transaction(vec![(calc_address(&hash(b"payee")), 10)], None).unwrap();
+        miner.initialize();
+        for _ in 0..2 { miner.mine_block(); }
+        miner.client.last_block().unwrap()
+    }
+
+    #[test]
+    fn survives_a_restart() {
+        let dir = data_dir("restart");
+        let tip = mine(&dir);
+        let client = reopen(&dir);
+        assert_eq!(client.last_block().unwrap().id(), tip.id());
+        assert_eq!(client.blocks.len(), 7);
+        assert_eq!(client.last_block().unwrap().balance_of(&calc_address(&hash(b"payee"))), 10);
+        assert_eq!(client.last_block().unwrap().balance_of(&client.address()), tip.balance_of(&client.address()));
+        assert_eq!(client.last_confirmed_block().unwrap().header.chain_length, tip.header.chain_length - 2);
+
+        //without the index everything is recovered from the log
+        drop(client);
+        fs::remove_file(dir.join(INDEX_FILE)).unwrap();
+        assert_eq!(reopen(&dir).last_block().unwrap().id(), tip.id());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_a_torn_or_corrupt_tail() {
+        let dir = data_dir("corrupt");
+        let tip = mine(&dir);
+        let blocks_path = dir.join(BLOCKS_FILE);
+        OpenOptions::new().append(true).open(&blocks_path).unwrap().write_all(&[5, 0, 0]).unwrap();
+        assert_eq!(reopen(&dir).last_block().unwrap().id(), tip.id());
+
+        //damage the tip's record but not its length prefix
+        let tip_start = fs::metadata(&blocks_path).unwrap().len() - tip.to_bytes().len() as u64;
+        let mut file = OpenOptions::new().write(true).open(&blocks_path).unwrap();
+        file.seek(SeekFrom::Start(tip_start + 40)).unwrap();
+        file.write_all(&[0xff; 4]).unwrap();
+        drop(file);
+        let mut client = reopen(&dir);
+        let restored = client.last_block().unwrap();
+        assert_eq!(restored.id(), tip.header.prev_block_hash);
+        assert!(!client.blocks.contains(&tip.id()));
+
+        //and the store takes new blocks again where the good ones end
+        client.receive_block(tip.clone()).unwrap();
+        drop(client);
+        assert_eq!(reopen(&dir).last_block().unwrap().id(), tip.id());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::cmp::Ordering;
 use std::fmt::{Debug, Formatter, write};
 use hex::encode;
@@ -6,11 +7,16 @@ use ring::signature::{Signature, UnparsedPublicKey};
 use super::*;
 use serde::*;
 use serde::ser::Serializer;
-use serde_json::*;
+use serde_json::to_string;
+use crate::encoding::{DecodeError, Decoder, Encoder};
+use crate::multisig::MultisigPolicy;
 
 
-#[derive(Clone, Serialize)]
-//#[derive(Clone)]
+/** Leads every transaction signing preimage, so a transaction signature can't pass for any other kind of signature. */
+pub const SIGNING_DOMAIN:&[u8] = b"rusted-gold transaction";
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Transaction {
     pub from:Address,
     pub nonce:u128,
@@ -18,7 +24,15 @@ pub struct Transaction {
     pub sig:Option<SigWrapper>,
     pub outputs:Vec<(Address, u128)>,
     pub fee: u32,
-    pub data: String
+    pub data: String,
+    /** Set when spending from a multisig account. `from` is then the policy's address,
+        and `cosignatures` replaces `pubkey_bytes` and `sig`.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multisig:Option<MultisigPolicy>,
+    /** Signatures keyed by the signer's index in the policy's keys. */
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub cosignatures:BTreeMap<u8, SigWrapper>
 }
 
 impl Debug for Transaction {
@@ -45,47 +59,328 @@ impl Transaction {
             sig:None,
             outputs,
             fee,
-            data
+            data,
+            multisig:None,
+            cosignatures:BTreeMap::new()
         }
     }
 
-    pub fn serialize(&self) -> String {
-        let serialized_from = to_string(&self.from).unwrap();
-        let serialized_nonce = to_string(&self.nonce).unwrap();
-        let serialized_pubkey_bytes = to_string(&self.pubkey_bytes).unwrap();
-        let serialized_outputs = to_string(&self.outputs).unwrap();
-        let serialized_fee = to_string(&self.fee).unwrap();
-        let serialized_data = to_string(&self.data).unwrap();
-        format!("{}||{}||{}||{}||{}||{}", serialized_from, serialized_nonce, serialized_pubkey_bytes, serialized_outputs, serialized_fee, serialized_data)
+    /** An unsigned transaction spending from `policy`'s account. Collect signatures with `cosign`. */
+    pub fn new_multisig(policy:MultisigPolicy, nonce:u128, outputs:Vec<(Address, u128)>, fee: u32, data: String) -> Self {
+        Transaction {
+            multisig:Some(policy.clone()),
+            ..Transaction::new(policy.address(), nonce, vec![], outputs, fee, data)
+        }
     }
 
-    pub fn id(&self) -> Hash {
-        Hash(hash(self.serialize().as_bytes()))
+    /** The reward transaction a block mints for its miner. It has no signature; the nonce
+        is the block height so coinbases of different blocks get different ids.
+     */
+    pub fn coinbase(chain_length:u32, reward_addr:Address, amount:u128) -> Self {
+        Transaction::new(Address::coinbase(), chain_length as u128, vec![], vec![(reward_addr, amount)], 0, String::new())
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.from.kind() == AddressKind::Coinbase && self.pubkey_bytes.is_empty() && self.sig.is_none()
+            && self.multisig.is_none() && self.cosignatures.is_empty()
+    }
+
+    // which keys and signatures a transaction carries has to fit who it is from
+    pub(crate) fn check_shape(&self) -> Result<(), String> {
+        if self.is_coinbase() {
+            return Ok(());
+        }
+        match &self.multisig {
+            Some(policy) => {
+                if !self.pubkey_bytes.is_empty() || self.sig.is_some() {
+                    return Err("a multisig transaction is signed with cosignatures only".to_string());
+                }
+                if self.cosignatures.keys().any(|index| usize::from(*index) >= policy.keys().len()) {
+                    return Err("cosignature from a key the policy doesn't have".to_string());
+                }
+            }
+            None => {
+                if self.pubkey_bytes.len() != PUBKEY_LEN {
+                    return Err(format!("{} byte public key", self.pubkey_bytes.len()));
+                }
+                if !self.cosignatures.is_empty() {
+                    return Err("cosignatures on a single key transaction".to_string());
+                }
+            }
+        }
+        Ok(())
     }
 
-    pub fn sign(&mut self, keypair:&Ed25519KeyPair) -> () {
-        self.sig = Some(SigWrapper(keypair.sign(self.id().as_ref())));
+    fn encode_fields(&self, encoder:&mut Encoder) {
+        encoder.address(&self.from);
+        encoder.u128(self.nonce);
+        encoder.bytes(&self.pubkey_bytes);
+        encoder.count(self.outputs.len());
+        for (address, amount) in &self.outputs {
+            encoder.address(address);
+            encoder.u128(*amount);
+        }
+        encoder.u32(self.fee);
+        encoder.str(&self.data);
+        match &self.multisig {
+            Some(policy) => {
+                encoder.u8(1);
+                policy.encode(encoder);
+            }
+            None => encoder.u8(0)
+        }
     }
 
-    pub fn valid_signature(&self) -> bool {
-        return match &self.sig {
+    /** The fields plus the optional signature, without a version byte. Blocks embed transactions this way. */
+    pub(crate) fn encode(&self, encoder:&mut Encoder) {
+        self.encode_fields(encoder);
+        match &self.sig {
             Some(sig) => {
-                let pubkey = UnparsedPublicKey::new(&signature::ED25519, &self.pubkey_bytes);
-                if pubkey.verify(self.id().as_ref(), sig.as_ref()).is_ok() { true } else { false }
+                encoder.u8(1);
+                encoder.fixed(sig);
+            }
+            None => encoder.u8(0)
+        }
+        encoder.count(self.cosignatures.len());
+        for (index, sig) in &self.cosignatures {
+            encoder.u8(*index);
+            encoder.fixed(sig);
+        }
+    }
+
+    pub(crate) fn decode(decoder:&mut Decoder) -> Result<Self, DecodeError> {
+        let from = decoder.address()?;
+        let nonce = decoder.u128()?;
+        let pubkey_bytes = decoder.bytes()?;
+        let mut outputs = vec![];
+        for _ in 0..decoder.count()? {
+            outputs.push((decoder.address()?, decoder.u128()?));
+        }
+        let fee = decoder.u32()?;
+        let data = decoder.string()?;
+        let multisig = match decoder.u8()? {
+            0 => None,
+            1 => Some(MultisigPolicy::decode(decoder)?),
+            flag => return Err(DecodeError::InvalidFlag(flag))
+        };
+        let sig = match decoder.u8()? {
+            0 => None,
+            1 => Some(SigWrapper(decoder.fixed(SIG_LEN)?)),
+            flag => return Err(DecodeError::InvalidFlag(flag))
+        };
+        let mut cosignatures = BTreeMap::new();
+        for _ in 0..decoder.count()? {
+            let index = decoder.u8()?;
+            //in index order, so every transaction has exactly one encoding
+            if cosignatures.keys().next_back().is_some_and(|last| *last >= index) {
+                return Err(DecodeError::Invalid("cosignatures out of order".to_string()));
+            }
+            cosignatures.insert(index, SigWrapper(decoder.fixed(SIG_LEN)?));
+        }
+        let tx = Transaction { from, nonce, pubkey_bytes, sig, outputs, fee, data, multisig, cosignatures };
+        tx.check_shape().map_err(DecodeError::Invalid)?;
+        Ok(tx)
+    }
+
+    /** What `id` hashes: the version byte and every field but the signature. */
+    pub fn id_preimage(&self) -> Vec<u8> {
+        let mut encoder = Encoder::versioned();
+        self.encode_fields(&mut encoder);
+        encoder.finish()
+    }
+
+    /** Canonical wire format. */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::versioned();
+        self.encode(&mut encoder);
+        encoder.finish()
+    }
+
+    /** Inverse of `to_bytes`. */
+    pub fn from_bytes(bytes:&[u8]) -> Result<Self, DecodeError> {
+        let mut decoder = Decoder::new(bytes);
+        decoder.version()?;
+        let tx = Transaction::decode(&mut decoder)?;
+        decoder.finish()?;
+        Ok(tx)
+    }
+
+    pub fn to_json(&self) -> String {
+        to_string(self).unwrap()
+    }
+
+    /** Inverse of `to_json`. Decoding then re-encoding gives back the same `id()`. */
+    pub fn from_json(json:&str) -> serde_json::Result<Self> {
+        let tx:Transaction = serde_json::from_str(json)?;
+        tx.check_shape().map_err(de::Error::custom)?;
+        Ok(tx)
+    }
+
+    pub fn id(&self) -> Hash {
+        Hash(hash(&self.id_preimage()))
+    }
+
+    /** Hash of the whole wire format, signatures included. Unlike `id`, it changes if a signature does,
+        which is why blocks commit to this.
+     */
+    pub fn witness_hash(&self) -> Hash {
+        Hash(hash(&self.to_bytes()))
+    }
+
+    /** What actually gets signed: the domain tag, the id of the chain's genesis block, then `id()`.
+        A signature is only good on the chain it was made for.
+     */
+    pub fn signing_preimage(&self, chain_id:&Hash) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.fixed(SIGNING_DOMAIN);
+        encoder.hash(chain_id);
+        encoder.hash(&self.id());
+        encoder.finish()
+    }
+
+    pub fn sign(&mut self, keypair:&Ed25519KeyPair, chain_id:&Hash) {
+        self.sig = Some(SigWrapper::from(keypair.sign(&self.signing_preimage(chain_id))));
+    }
+
+    /** Adds this key's signature to a multisig transaction. Errors if the key isn't one of the policy's. */
+    pub fn cosign(&mut self, keypair:&Ed25519KeyPair, chain_id:&Hash) -> Result<(), ValidationError> {
+        let policy = self.multisig.as_ref().ok_or_else(|| ValidationError::InvalidMultisig("not a multisig transaction".to_string()))?;
+        let public_key = keypair.public_key().as_ref();
+        let index = policy.index_of(public_key).ok_or_else(|| ValidationError::NotACosigner(calc_address(public_key)))?;
+        let sig = SigWrapper::from(keypair.sign(&self.signing_preimage(chain_id)));
+        self.cosignatures.insert(index, sig);
+        Ok(())
+    }
+
+    /** How many more cosignatures a multisig transaction needs. Always 0 for any other transaction. */
+    pub fn signatures_missing(&self) -> usize {
+        self.multisig.as_ref().map_or(0, |policy| usize::from(policy.threshold()).saturating_sub(self.cosignatures.len()))
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.sig.is_some() || !self.cosignatures.is_empty()
+    }
+
+    /** Checks the signature, or for a multisig transaction that every cosignature is valid and there are enough of them. */
+    pub fn check_signatures(&self, chain_id:&Hash) -> Result<(), ValidationError> {
+        let preimage = self.signing_preimage(chain_id);
+        let verify = |key:&[u8], sig:&SigWrapper| UnparsedPublicKey::new(&signature::ED25519, key).verify(&preimage, sig.as_ref()).is_ok();
+        match &self.multisig {
+            Some(policy) => {
+                if self.cosignatures.is_empty() { return Err(ValidationError::MissingSignature) }
+                for (index, sig) in &self.cosignatures {
+                    let key = policy.keys().get(usize::from(*index)).ok_or(ValidationError::BadSignature)?;
+                    if !verify(key, sig) { return Err(ValidationError::BadSignature) }
+                }
+                if self.cosignatures.len() < usize::from(policy.threshold()) {
+                    return Err(ValidationError::NotEnoughSignatures { required: policy.threshold(), valid: self.cosignatures.len() });
+                }
+                Ok(())
+            }
+            None => match &self.sig {
+                Some(sig) if verify(&self.pubkey_bytes, sig) => Ok(()),
+                Some(_) => Err(ValidationError::BadSignature),
+                None => Err(ValidationError::MissingSignature)
             }
-            None => { false }
         }
     }
 
+    pub fn valid_signature(&self, chain_id:&Hash) -> bool {
+        self.check_signatures(chain_id).is_ok()
+    }
+
+    /** Errors unless `from` is the address of the key, or the multisig policy, that signs. */
+    pub fn check_sender(&self) -> Result<(), ValidationError> {
+        let derived = match &self.multisig {
+            Some(policy) => policy.address(),
+            None => calc_address(&self.pubkey_bytes)
+        };
+        if derived != self.from {
+            return Err(ValidationError::AddressMismatch { from: self.from.clone(), derived });
+        }
+        Ok(())
+    }
+
+    /** Errors if any output pays an address that can't receive gold. */
+    pub fn check_outputs(&self) -> Result<(), ValidationError> {
+        match self.outputs.iter().find(|(address, _)| !address.can_receive()) {
+            Some((address, _)) => Err(ValidationError::InvalidOutput { tx_id: self.id(), address: address.clone() }),
+            None => Ok(())
+        }
+    }
+
+    /** Checks the sender's key, the output addresses, the signature against `block`'s chain, and the sender's balance in `block`. */
+    pub fn validate(&self, block:&Block) -> Result<(), ValidationError> {
+        if !self.is_signed() {return Err(ValidationError::MissingSignature);}
+        self.check_sender()?;
+        self.check_outputs()?;
+        self.check_signatures(&block.chain_id())?;
+        let required = self.total_output()?;
+        let available = block.balance_of(&self.from);
+        if required > available {
+            return Err(ValidationError::InsufficientFunds { address: self.from.clone(), available, required });
+        }
+        Ok(())
+    }
+
     pub fn sufficient_funds(&self, block:&Block) -> bool {
-        self.total_output() <= block.balance_of(&self.from)
+        self.total_output().is_ok_and(|required| required <= block.balance_of(&self.from))
     }
 
-    pub fn total_output(&self) -> u128 {
-        let mut sum:u128 = 0;
-        for i in 0..self.outputs.len() {
-            sum+=self.outputs[i].1
+    /** Writes every address in this tx with the prefix `hrp`. Nothing hashed or signed changes. */
+    pub fn set_network(&mut self, hrp:&str) {
+        self.from = self.from.clone().on_network(hrp);
+        for (address, _) in &mut self.outputs {
+            *address = address.clone().on_network(hrp);
         }
-        sum + u128::from(self.fee)
     }
-}
\ No newline at end of file
+
+    /** Everything the sender pays: the outputs plus the fee. Errors if that doesn't fit in a u128. */
+    pub fn total_output(&self) -> Result<u128, ValidationError> {
+        let mut sum = u128::from(self.fee);
+        for (_, amount) in &self.outputs {
+            sum = sum.checked_add(*amount).ok_or_else(|| ValidationError::OutputOverflow(self.id()))?;
+        }
+        Ok(sum)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use crate::{calc_address, Address, Blockchain, MemoryBlockStore, Transaction, ValidationError};
+
+    #[test]
+    fn signature_only_counts_on_its_own_chain() {
+        let chain = Blockchain::new().with_pow_leading_zeros(0).with_retarget_interval(0);
+        let payer = Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap();
+        let from = calc_address(payer.public_key().as_ref());
+        //same rules and the same payer, only a different allocation
+        let mainnet = chain.make_genesis(BTreeMap::from([(from.clone(), 100)]));
+        let fork = chain.make_genesis(BTreeMap::from([(from.clone(), 100), (Address::from_public_key(&[2; 32]), 1)]));
+        let mut tx = Transaction::new(from, 0, payer.public_key().as_ref().to_vec(), vec![(Address::from_public_key(&[3; 32]), 10)], 1, String::new());
+        tx.sign(&payer, &mainnet.chain_id());
+        assert_eq!(tx.check_signatures(&mainnet.chain_id()), Ok(()));
+        assert_eq!(tx.check_signatures(&fork.chain_id()), Err(ValidationError::BadSignature));
+        let store = MemoryBlockStore::new();
+        let mut replayed = chain.make_block(Address::from_public_key(&[4; 32]), &fork, &store).unwrap();
+        assert_eq!(replayed.add_transaction(tx.clone()), Err(ValidationError::BadSignature));
+        assert!(chain.make_block(Address::from_public_key(&[4; 32]), &mainnet, &store).unwrap().add_transaction(tx).is_ok());
+    }
+
+    #[test]
+    fn sender_must_own_the_signing_key() {
+        let chain = Blockchain::new().with_pow_leading_zeros(0).with_retarget_interval(0);
+        let victim = Address::from_public_key(&[5; 32]);
+        let thief = Ed25519KeyPair::from_seed_unchecked(&[6; 32]).unwrap();
+        let genesis = chain.make_genesis(BTreeMap::from([(victim.clone(), 100)]));
+        //validly signed, by a key that isn't the victim's
+        let mut tx = Transaction::new(victim.clone(), 0, thief.public_key().as_ref().to_vec(), vec![(calc_address(thief.public_key().as_ref()), 50)], 1, String::new());
+        tx.sign(&thief, &genesis.chain_id());
+        assert_eq!(tx.check_signatures(&genesis.chain_id()), Ok(()));
+        let mismatch = ValidationError::AddressMismatch { from: victim, derived: calc_address(thief.public_key().as_ref()) };
+        assert_eq!(tx.check_sender(), Err(mismatch.clone()));
+        let mut block = chain.make_block(Address::from_public_key(&[4; 32]), &genesis, &MemoryBlockStore::new()).unwrap();
+        assert_eq!(block.add_transaction(tx), Err(mismatch));
+    }
+}
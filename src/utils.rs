@@ -1,4 +1,3 @@
-use base64::{encode, encode_config};
 use ring::{digest, rand, signature::{self, KeyPair, Ed25519KeyPair}};
 use crate::Address;
 
@@ -16,7 +15,7 @@ pub fn hash(bytes: &[u8]) -> Vec<u8> {
 }
 
 pub fn calc_address(pub_key: &[u8]) -> Address {
-    encode_config(pub_key,base64::STANDARD)
+    Address::from_public_key(pub_key)
 }
 
 fn test() {
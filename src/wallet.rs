@@ -0,0 +1,334 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use crate::{calc_address, Address, MAINNET_HRP, PUBKEY_LEN};
+
+pub const KEYSTORE_VERSION:u32 = 2;
+// first release, whose addresses were base64 and whose associated data carried the version
+const KEYSTORE_V1:u32 = 1;
+const SALT_LEN:usize = 32;
+const KEY_LEN:usize = 32;
+// limits on the scrypt parameters a keystore file can ask for, so it can't make an unlock
+// allocate gigabytes or run for hours
+const MAX_LOG_N:u8 = 22;
+const MAX_R:u32 = 32;
+const MAX_P:u32 = 16;
+// scrypt needs 128·r·2^log_n bytes, this is what MAX_LOG_N takes at the default r
+const MAX_KDF_MEMORY:u64 = (128 * 8) << MAX_LOG_N;
+
+/** Why a keystore operation failed. */
+#[derive(Debug)]
+pub enum WalletError {
+    Io(io::Error),
+    Format(String),
+    UnsupportedVersion(u32),
+    KeyExists(String),
+    NoSuchKey(String),
+    WrongPassphrase,
+    BadKey
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletError::Io(err) => write!(f, "keystore io error: {}", err),
+            WalletError::Format(reason) => write!(f, "malformed keystore: {}", reason),
+            WalletError::UnsupportedVersion(version) => write!(f, "unsupported keystore version {}", version),
+            WalletError::KeyExists(name) => write!(f, "a key named {} already exists", name),
+            WalletError::NoSuchKey(name) => write!(f, "no key named {}", name),
+            WalletError::WrongPassphrase => write!(f, "wrong passphrase, or the keystore was tampered with"),
+            WalletError::BadKey => write!(f, "not a valid ed25519 key")
+        }
+    }
+}
+
+impl Error for WalletError {}
+
+impl From<io::Error> for WalletError {
+    fn from(err:io::Error) -> Self {
+        WalletError::Io(err)
+    }
+}
+
+/** scrypt cost parameters. Each key records the ones it was sealed with. */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    pub log_n:u8,
+    pub r:u32,
+    pub p:u32
+}
+
+impl Default for KdfParams {
+    /** 32 MiB and a fraction of a second per unlock. */
+    fn default() -> Self {
+        KdfParams { log_n: 15, r: 8, p: 1 }
+    }
+}
+
+impl KdfParams {
+    fn derive(&self, passphrase:&str, salt:&[u8]) -> Result<[u8; KEY_LEN], WalletError> {
+        if self.log_n > MAX_LOG_N {
+            return Err(WalletError::Format(format!("scrypt log_n {} is too large", self.log_n)));
+        }
+        if self.r > MAX_R || self.p > MAX_P {
+            return Err(WalletError::Format(format!("scrypt r {} and p {} are too large", self.r, self.p)));
+        }
+        if (128 * u64::from(self.r)) << self.log_n > MAX_KDF_MEMORY {
+            return Err(WalletError::Format(format!("scrypt log_n {} with r {} needs too much memory", self.log_n, self.r)));
+        }
+        let params = scrypt::Params::new(self.log_n, self.r, self.p, KEY_LEN)
+            .map_err(|_| WalletError::Format("invalid scrypt parameters".to_string()))?;
+        let mut key = [0u8; KEY_LEN];
+        scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key)
+            .map_err(|_| WalletError::Format("invalid scrypt output length".to_string()))?;
+        Ok(key)
+    }
+}
+
+/** One key as it sits on disk. The secret is the PKCS#8 document, sealed with ChaCha20-Poly1305
+    under a key scrypt derives from the passphrase. The name and public key are bound in as
+    associated data, so entries can't be swapped around.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptedKey {
+    pub public_key:String,
+    pub address:Address,
+    pub kdf:KdfParams,
+    salt:String,
+    nonce:String,
+    ciphertext:String,
+    /** Set on keys migrated from a v1 keystore, which were sealed under the v1 associated data. */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sealed_in:Option<u32>
+}
+
+/** A key as v1 keystores stored it. Its base64 `address` is left out, the public key says the same. */
+#[derive(Deserialize)]
+struct EncryptedKeyV1 {
+    public_key:String,
+    kdf:KdfParams,
+    salt:String,
+    nonce:String,
+    ciphertext:String
+}
+
+impl EncryptedKeyV1 {
+    fn migrate(self) -> Result<EncryptedKey, WalletError> {
+        let public_key = decode_hex("public_key", &self.public_key)?;
+        if public_key.len() != PUBKEY_LEN {
+            return Err(WalletError::Format("public_key has the wrong length".to_string()));
+        }
+        Ok(EncryptedKey {
+            address: Address::from_public_key(&public_key),
+            public_key: self.public_key,
+            kdf: self.kdf,
+            salt: self.salt,
+            nonce: self.nonce,
+            ciphertext: self.ciphertext,
+            sealed_in: Some(KEYSTORE_V1)
+        })
+    }
+}
+
+fn associated_data(sealed_in:Option<u32>, name:&str, public_key:&str) -> Vec<u8> {
+    match sealed_in {
+        Some(KEYSTORE_V1) => format!("rusted-gold keystore v{}|{}|{}", KEYSTORE_V1, name, public_key).into_bytes(),
+        _ => format!("rusted-gold keystore|{}|{}", name, public_key).into_bytes()
+    }
+}
+
+fn decode_hex(field:&str, value:&str) -> Result<Vec<u8>, WalletError> {
+    hex::decode(value).map_err(|_| WalletError::Format(format!("{} is not hex", field)))
+}
+
+impl EncryptedKey {
+    fn seal(name:&str, pkcs8:&[u8], passphrase:&str, kdf:KdfParams) -> Result<Self, WalletError> {
+        let keypair = Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|_| WalletError::BadKey)?;
+        let public_key = hex::encode(keypair.public_key().as_ref());
+        let rng = SystemRandom::new();
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill(&mut salt).map_err(|_| WalletError::Format("no randomness available".to_string()))?;
+        rng.fill(&mut nonce).map_err(|_| WalletError::Format("no randomness available".to_string()))?;
+
+        let key = kdf.derive(passphrase, &salt)?;
+        let sealing_key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap());
+        let mut ciphertext = pkcs8.to_vec();
+        sealing_key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(associated_data(None, name, &public_key)),
+            &mut ciphertext
+        ).map_err(|_| WalletError::BadKey)?;
+
+        Ok(EncryptedKey {
+            address: calc_address(keypair.public_key().as_ref()),
+            public_key,
+            kdf,
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+            sealed_in: None
+        })
+    }
+
+    fn open(&self, name:&str, passphrase:&str) -> Result<Ed25519KeyPair, WalletError> {
+        let salt = decode_hex("salt", &self.salt)?;
+        let nonce:[u8; NONCE_LEN] = decode_hex("nonce", &self.nonce)?.try_into()
+            .map_err(|_| WalletError::Format("nonce has the wrong length".to_string()))?;
+        let mut ciphertext = decode_hex("ciphertext", &self.ciphertext)?;
+
+        let key = self.kdf.derive(passphrase, &salt)?;
+        let opening_key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap());
+        let pkcs8 = opening_key.open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(associated_data(self.sealed_in, name, &self.public_key)),
+            &mut ciphertext
+        ).map_err(|_| WalletError::WrongPassphrase)?;
+        let keypair = Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|_| WalletError::BadKey)?;
+        if hex::encode(keypair.public_key().as_ref()) != self.public_key {
+            return Err(WalletError::BadKey);
+        }
+        Ok(keypair)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeystoreFile {
+    version:u32,
+    keys:BTreeMap<String, EncryptedKey>
+}
+
+#[derive(Deserialize)]
+struct KeystoreFileV1 {
+    keys:BTreeMap<String, EncryptedKeyV1>
+}
+
+/** Named signing keys in a JSON file, each encrypted under a passphrase.
+    Public keys and addresses stay readable without one.
+ */
+pub struct Keystore {
+    path:PathBuf,
+    kdf:KdfParams,
+    address_hrp:String,
+    keys:BTreeMap<String, EncryptedKey>
+}
+
+impl Keystore {
+    /** Loads the keystore at `path`, or starts an empty one there if the file doesn't exist yet.
+        A v1 keystore is rewritten as the current version. Its keys keep their passphrases.
+     */
+    pub fn open<P: AsRef<Path>>(path:P) -> Result<Self, WalletError> {
+        let path = path.as_ref().to_path_buf();
+        let mut migrated = false;
+        let keys = match fs::read_to_string(&path) {
+            Ok(json) => {
+                let file:serde_json::Value = serde_json::from_str(&json).map_err(|err| WalletError::Format(err.to_string()))?;
+                //check the version first, older layouts won't parse as this one
+                match file.get("version").and_then(|version| version.as_u64()) {
+                    Some(version) if version == u64::from(KEYSTORE_VERSION) => {
+                        let file:KeystoreFile = serde_json::from_value(file).map_err(|err| WalletError::Format(err.to_string()))?;
+                        file.keys
+                    }
+                    Some(version) if version == u64::from(KEYSTORE_V1) => {
+                        let file:KeystoreFileV1 = serde_json::from_value(file).map_err(|err| WalletError::Format(err.to_string()))?;
+                        migrated = true;
+                        file.keys.into_iter()
+                            .map(|(name, entry)| Ok((name, entry.migrate()?)))
+                            .collect::<Result<_, WalletError>>()?
+                    }
+                    Some(version) => return Err(WalletError::UnsupportedVersion(u32::try_from(version).unwrap_or(u32::MAX))),
+                    None => return Err(WalletError::Format("no version".to_string()))
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err.into())
+        };
+        let keystore = Keystore { path, kdf: KdfParams::default(), address_hrp: MAINNET_HRP.to_string(), keys };
+        if migrated {
+            keystore.save()?;
+        }
+        Ok(keystore)
+    }
+
+    /** scrypt cost for keys sealed from now on. Existing keys keep theirs. */
+    pub fn with_kdf_params(mut self, kdf:KdfParams) -> Self {
+        self.kdf = kdf;
+        self
+    }
+
+    /** Network prefix the keys' addresses are written with, e.g. `Blockchain::address_hrp()`. */
+    pub fn with_address_hrp(mut self, address_hrp:&str) -> Self {
+        for entry in self.keys.values_mut() {
+            entry.address = entry.address.clone().on_network(address_hrp);
+        }
+        self.address_hrp = address_hrp.to_string();
+        self
+    }
+
+    // writes to a temporary file first so a crash can't leave a half written keystore
+    fn save(&self) -> Result<(), WalletError> {
+        let file = KeystoreFile { version: KEYSTORE_VERSION, keys: self.keys.clone() };
+        let json = serde_json::to_string_pretty(&file).map_err(|err| WalletError::Format(err.to_string()))?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, json)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /** Generates a new key under `name` and returns it. */
+    pub fn create(&mut self, name:&str, passphrase:&str) -> Result<Ed25519KeyPair, WalletError> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|_| WalletError::BadKey)?;
+        self.import_pkcs8(name, pkcs8.as_ref(), passphrase)
+    }
+
+    /** Adds an existing PKCS#8 encoded key, such as an old `./key` file. */
+    pub fn import_pkcs8(&mut self, name:&str, pkcs8:&[u8], passphrase:&str) -> Result<Ed25519KeyPair, WalletError> {
+        if self.keys.contains_key(name) {
+            return Err(WalletError::KeyExists(name.to_string()));
+        }
+        let mut entry = EncryptedKey::seal(name, pkcs8, passphrase, self.kdf)?;
+        entry.address = entry.address.on_network(&self.address_hrp);
+        self.keys.insert(name.to_string(), entry);
+        self.save()?;
+        Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|_| WalletError::BadKey)
+    }
+
+    /** Decrypts the key called `name`. */
+    pub fn unlock(&self, name:&str, passphrase:&str) -> Result<Ed25519KeyPair, WalletError> {
+        self.entry(name)?.open(name, passphrase)
+    }
+
+    /** Every key's name and address. */
+    pub fn list(&self) -> Vec<(String, Address)> {
+        self.keys.iter().map(|(name, entry)| (name.clone(), entry.address.clone())).collect()
+    }
+
+    /** The raw public key of `name`. No passphrase needed. */
+    pub fn export_public(&self, name:&str) -> Result<Vec<u8>, WalletError> {
+        decode_hex("public_key", &self.entry(name)?.public_key)
+    }
+
+    pub fn address(&self, name:&str) -> Result<Address, WalletError> {
+        Ok(self.entry(name)?.address.clone())
+    }
+
+    /** Removes `name` for good. Takes the passphrase so a key can't be dropped by accident. */
+    pub fn delete(&mut self, name:&str, passphrase:&str) -> Result<(), WalletError> {
+        self.unlock(name, passphrase)?;
+        self.keys.remove(name);
+        self.save()
+    }
+
+    fn entry(&self, name:&str) -> Result<&EncryptedKey, WalletError> {
+        self.keys.get(name).ok_or_else(|| WalletError::NoSuchKey(name.to_string()))
+    }
+}